@@ -2,21 +2,70 @@ export declare enum ConnectionState {
     Up = 0,
     Down = 1
 }
+interface Welcome {
+    version: number;
+    session_id: number;
+    encoding: string;
+    keys: string[];
+    events: string[];
+    rpcs: string[];
+}
 export declare class Poca {
     readonly addr: string;
     private identifier;
     private ws?;
     private raw;
-    private work_pool;
+    private confirmed;
+    private versions;
+    private outbox;
+    private message_id;
+    private revision;
+    private scope_revisions;
+    private subscriptions;
+    private synced;
+    private mark_synced;
     private get_queue;
+    private listeners;
+    private pending_calls;
     state: ConnectionState;
+    session?: Welcome;
     constructor(addr: string);
     connect(): Promise<void>;
+    private encode;
+    private decode;
     close(): void;
+    private send;
+    private stale;
+    private apply_update;
+    private receive;
+    private forget;
+    private resync;
     private get_data;
     private set_data;
+    version(key: string): number | undefined;
+    items<V>(key: string): {
+        [item: string]: V;
+    };
+    update<T>(key: string, value: T, expected_version?: number): void;
     reactive<T extends Object, K extends keyof T>(key: string): Promise<T>;
     reactive_with_default<T extends Object, K extends keyof T>(key: string, initial_value: T): T;
-    emit(key: string): void;
+    emit(key: string, payload?: any): void;
+    on<P>(key: string, listener: (payload: P) => void): () => void;
+    snapshot(scope: string): Promise<{
+        [key: string]: {
+            data: any;
+            version: number;
+        };
+    }>;
+    subscribe(scope: string): Promise<{
+        [key: string]: {
+            data: any;
+            version: number;
+        };
+    }>;
+    unsubscribe(scope: string): void;
+    private request;
+    call<Req, Resp>(key: string, request: Req): Promise<Resp>;
 }
 export declare function effect(inner: () => void): void;
+export {};
@@ -24,3 +24,124 @@ test("Side effects", () => {
   handle["id"] = 1919810;
   expect(listener.modified).toBe(true);
 });
+
+// stands in for the server's socket
+class MockSocket {
+  static last: MockSocket;
+  frames: Uint8Array[] = [];
+  binaryType = "";
+  onopen?: () => void;
+  onmessage?: (event: {data: string}) => void;
+
+  constructor(readonly url: string) {
+    MockSocket.last = this;
+    setTimeout(() => this.onopen!(), 0);
+  }
+
+  send(frame: Uint8Array) {
+    this.frames.push(frame);
+  }
+
+  // the frames sent so far, for connections using JSON
+  get sent(): any[] {
+    return this.frames.map((frame) => JSON.parse(new TextDecoder().decode(frame)));
+  }
+
+  close() {}
+
+  receive(message: any) {
+    this.onmessage!({data: JSON.stringify(message)});
+  }
+
+  receive_binary(frame: Uint8Array) {
+    this.onmessage!({data: frame.buffer});
+  }
+}
+
+function from_hex(hex: string): Uint8Array {
+  return new Uint8Array(hex.match(/../g)!.map((byte) => parseInt(byte, 16)));
+}
+
+function to_hex(bytes: Uint8Array): string {
+  return Array.from(bytes, (byte) => byte.toString(16).padStart(2, "0")).join("");
+}
+
+async function handshake(encoding: string) {
+  (globalThis as any).WebSocket = MockSocket;
+  const poca = new Poca("localhost:1145");
+  poca.connect();
+  await new Promise((resolve) => setTimeout(resolve, 0));
+  const socket = MockSocket.last;
+  // the Welcome is always JSON
+  socket.receive({
+    message_type: 6,
+    data: {version: 1, session_id: 0, encoding, keys: [], events: [], rpcs: []},
+  });
+  return {poca, socket};
+}
+
+async function connected(values: {[key: string]: {data: any; version: number}}) {
+  const {poca, socket} = await handshake("json");
+  socket.receive({message_type: 7, revision: 0, data: values});
+  return {poca, socket};
+}
+
+test("Own writes echoed back as patches are applied once", async () => {
+  const {poca, socket} = await connected({todo: {data: {items: ["a"]}, version: 0}});
+  const todo: {items: string[]} = await poca.reactive("todo");
+  todo.items = [...todo.items, "b"];
+  // the server's diff of the write goes to every client, the writer included
+  socket.receive({
+    message_type: 8,
+    key: "todo",
+    version: 1,
+    revision: 1,
+    data: [{op: "add", path: "/items/1", value: "b"}],
+  });
+  expect(todo.items).toEqual(["a", "b"]);
+  expect(poca.version("todo")).toBe(1);
+  todo.items = [...todo.items, "c"];
+  expect(socket.sent[socket.sent.length - 1].data).toEqual({items: ["a", "b", "c"]});
+});
+
+// produced by rmp_serde::to_vec_named on the server, see client_frames in server/src/codec.rs
+const SNAPSHOT_FRAME =
+  "83ac6d6573736167655f7479706507a46461746182a76e756d6265727382a464617461de0010a466" +
+  "6c6167c3a5666c6f6174cb3fe0000000000000a3693136d1fc18a3693332d2fffe7960a3693634d3" +
+  "ffffff0000000000a26938d09ca46c697374dc0010000102030405060708090a0b0c0d0e0fa96c6f" +
+  "6e675f74657874d92861616161616161616161616161616161616161616161616161616161616161" +
+  "616161616161616161a76d697373696e67c0a86e65676174697665fba66e657374656482a5656d70" +
+  "747990a36d617080a474657874a668c3a96c6c6fa3753136cdea60a3753332ce00011170a3753634" +
+  "cf0000010000000000a27538ccc8a776657273696f6e02a4746f646f82a46461746182a4646f6e65" +
+  "c2a57469746c65a46d696c6ba776657273696f6e00a87265766973696f6e03";
+const SET_FRAME =
+  "84ac6d6573736167655f7479706501a2696401a36b6579a4746f646fa46461746182a4646f6e65c3" +
+  "a57469746c65a46d696c6b";
+
+test("MessagePack frames match the server's codec", async () => {
+  const {poca, socket} = await handshake("msgpack");
+  socket.receive_binary(from_hex(SNAPSHOT_FRAME));
+  const numbers = await poca.reactive("numbers");
+  expect(numbers).toEqual({
+    flag: true,
+    float: 0.5,
+    i16: -1000,
+    i32: -100000,
+    i64: -1099511627776,
+    i8: -100,
+    list: [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15],
+    long_text: "a".repeat(40),
+    missing: null,
+    negative: -5,
+    nested: {empty: [], map: {}},
+    text: "héllo",
+    u16: 60000,
+    u32: 70000,
+    u64: 1099511627776,
+    u8: 200,
+  });
+  expect(poca.version("numbers")).toBe(2);
+  const todo: {done: boolean; title: string} = await poca.reactive("todo");
+  todo.done = true;
+  expect(to_hex(socket.frames[socket.frames.length - 1])).toBe(SET_FRAME);
+});
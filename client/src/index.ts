@@ -3,90 +3,334 @@ enum WSMessageType {
   Emit = 2,
   Get = 3,
   Error = 4,
+  Hello = 5,
+  Welcome = 6,
+  Snapshot = 7,
+  Patch = 8,
+  Call = 9,
+  Reply = 10,
+  Batch = 11,
+  Removed = 12,
+  Subscribe = 13,
+  Unsubscribe = 14,
 }
 
+const PROTOCOL_VERSION = 1;
+// in order of preference, Hello and Welcome are always JSON
+const SUPPORTED_ENCODINGS = ["msgpack", "json"];
+
 export enum ConnectionState {
   Up,
   Down,
 }
 
+interface WSError {
+  code: string;
+  message: string;
+}
+
+interface Welcome {
+  version: number;
+  session_id: number;
+  encoding: string;
+  keys: string[];
+  events: string[];
+  rpcs: string[];
+}
+
 interface WSMessage {
   message_type: WSMessageType;
+  id?: number;
   key?: string;
-  data?: string;
+  data?: any;
+  revision?: number;
+  version?: number;
+  expected_version?: number;
+  error?: WSError;
 }
 
 export class Poca {
   private identifier!: symbol;
   private ws?: WebSocket;
   private raw: {[key: string]: any} = {};
-  private work_pool: string[] = [];
+  // the last value the server sent for each key, proxies write into `raw` before the
+  // server echoes their write back, so Patch frames are applied to a copy of this
+  private confirmed: {[key: string]: any} = {};
+  private versions: {[key: string]: number} = {};
+  private outbox: WSMessage[] = [];
+  private message_id: number = 0;
+  // revision of the last snapshot, Set frames older than it are stale
+  private revision: number = -1;
+  private scope_revisions: {[scope: string]: number} = {};
+  // scopes subscribed to on this connection, the server sends nothing outside them
+  private subscriptions: string[] = [];
+  private synced: Promise<void>;
+  private mark_synced!: () => void;
   private get_queue: {
-    [key: string]: ((value: string | PromiseLike<string>) => void)[];
+    [key: string]: ((value: any) => void)[];
+  } = {};
+  private listeners: {[key: string]: ((payload: any) => void)[]} = {};
+  // requests waiting for their Reply or scope Snapshot, by message id
+  private pending_calls: {
+    [id: number]: {resolve: (value: any) => void; reject: (error: WSError) => void};
   } = {};
   state: ConnectionState = ConnectionState.Down;
+  session?: Welcome;
 
   constructor(readonly addr: string) {
     this.identifier = Symbol();
     effect_callbacks[this.identifier] = {};
+    this.synced = new Promise((resolve) => (this.mark_synced = resolve));
   }
 
   async connect(): Promise<void> {
     let that = this;
     new Promise((resolve) => {
       that.ws?.close();
+      that.session = undefined;
+      that.subscriptions = [];
       that.ws = new WebSocket("ws://" + this.addr);
+      that.ws.binaryType = "arraybuffer";
       that.ws.onopen = () => {
-        that.state = ConnectionState.Up;
         that.ws!.onmessage = (event: MessageEvent<any>) => {
-          const message: WSMessage = JSON.parse(event.data);
+          const message: WSMessage = that.decode(event.data);
           switch (message.message_type) {
+            case WSMessageType.Welcome:
+              that.session = message.data;
+              that.state = ConnectionState.Up;
+              that.outbox.forEach((message) =>
+                that.ws!.send(that.encode(message))
+              );
+              that.outbox = [];
+              break;
+            case WSMessageType.Snapshot:
+              const values: {[key: string]: {data: any; version: number}} =
+                message.data;
+              // keys the snapshot covers but does not contain are gone
+              const scopes =
+                message.key != null ? [message.key] : that.subscriptions;
+              for (const key in that.raw) {
+                if (
+                  !(key in values) &&
+                  (scopes.length == 0 || scopes.some((scope) => in_scope(key, scope)))
+                ) {
+                  that.forget(key);
+                }
+              }
+              for (const key in values) {
+                that.receive(key, values[key].data, values[key].version);
+                effect_callbacks[that.identifier][key]?.forEach((callback) =>
+                  callback()
+                );
+              }
+              if (message.key != null) {
+                // a snapshot of one scope only covers the keys in it
+                that.scope_revisions[message.key] = message.revision!;
+                that.pending_calls[message.id!]?.resolve(values);
+                delete that.pending_calls[message.id!];
+                break;
+              }
+              that.revision = message.revision!;
+              that.mark_synced();
+              resolve(undefined);
+              break;
             case WSMessageType.Get:
-              if (this.get_queue[message.key!].length > 0) {
-                this.get_queue[message.key!].shift()?.(message.data!);
+              if (this.get_queue[message.key!]?.length > 0) {
+                that.receive(message.key!, message.data, message.version!);
+                this.get_queue[message.key!].shift()?.(that.raw[message.key!]);
+              } else if (
+                !(message.key! in that.versions) ||
+                message.version! >= that.versions[message.key!]
+              ) {
+                // a resync requested after an unusable Patch, or the current value
+                // after a rejected write
+                that.receive(message.key!, message.data, message.version!);
+                effect_callbacks[that.identifier][message.key!]?.forEach(
+                  (callback) => callback()
+                );
               }
               break;
+            case WSMessageType.Patch:
             case WSMessageType.Set:
-              this.raw[message.key!] = JSON.parse(message.data!);
-              //only call callbacks if values are different
-              //or should I
-              effect_callbacks[this.identifier][message.key!]?.forEach(
-                (callback) => callback()
+              if (that.apply_update(message)) {
+                //only call callbacks if values are different
+                //or should I
+                effect_callbacks[that.identifier][message.key!]?.forEach(
+                  (callback) => callback()
+                );
+              }
+              break;
+            case WSMessageType.Batch: {
+              // apply every update before running any callback
+              const updates: WSMessage[] = message.data;
+              const changed = new Set<string>();
+              for (const update of updates) {
+                if (update.message_type == WSMessageType.Error) {
+                  console.error(
+                    `Error ${update.error?.code} on key ${update.key}: ${update.error?.message}`
+                  );
+                } else if (that.apply_update(update)) {
+                  changed.add(update.key!);
+                }
+              }
+              changed.forEach((key) =>
+                effect_callbacks[that.identifier][key]?.forEach((callback) =>
+                  callback()
+                )
+              );
+              break;
+            }
+            case WSMessageType.Removed:
+              if (that.stale(message.key!, message.revision)) {
+                break;
+              }
+              // effects stay registered, a key registered again under the same
+              // name is announced with a Set and drives them as before
+              that.forget(message.key!);
+              break;
+            case WSMessageType.Emit:
+              that.listeners[message.key!]?.forEach((listener) =>
+                listener(message.data)
+              );
+              break;
+            case WSMessageType.Reply:
+              that.pending_calls[message.id!]?.resolve(message.data);
+              delete that.pending_calls[message.id!];
+              break;
+            case WSMessageType.Error:
+              if (message.id != null && message.id in that.pending_calls) {
+                that.pending_calls[message.id].reject(message.error!);
+                delete that.pending_calls[message.id];
+                break;
+              }
+              console.error(
+                `Error ${message.error?.code} on key ${message.key} (message ${message.id}): ${message.error?.message}`
               );
               break;
             default:
               console.log("Unimplemented message: " + message);
           }
         };
-        that.work_pool.forEach((key) => {
-          let message: WSMessage = {
-            message_type: WSMessageType.Get,
-            key,
-          };
-          that.ws!.send(JSON.stringify(message));
-        });
-        that.work_pool = [];
-        resolve(undefined);
+        const hello: WSMessage = {
+          message_type: WSMessageType.Hello,
+          id: that.message_id++,
+          data: {
+            version: PROTOCOL_VERSION,
+            encodings: SUPPORTED_ENCODINGS,
+          },
+        };
+        that.ws!.send(that.encode(hello));
       };
     });
   }
 
+  // the negotiated encoding applies once the Welcome has arrived
+  private encode(message: WSMessage): Uint8Array {
+    if (this.session?.encoding == "msgpack") {
+      return encode_msgpack(message);
+    }
+    return new TextEncoder().encode(JSON.stringify(message));
+  }
+
+  private decode(data: ArrayBuffer | string): WSMessage {
+    if (typeof data == "string") {
+      return JSON.parse(data);
+    }
+    const bytes = new Uint8Array(data);
+    if (this.session?.encoding == "msgpack") {
+      return decode_msgpack(bytes);
+    }
+    return JSON.parse(new TextDecoder().decode(bytes));
+  }
+
   close() {
     this.ws?.close();
     this.state = ConnectionState.Down;
   }
 
-  private async get_data(key: string): Promise<string> {
+  private send(message: WSMessage) {
+    if (this.state == ConnectionState.Up) {
+      this.ws?.send(this.encode(message));
+    } else {
+      this.outbox.push(message);
+    }
+  }
+
+  // frames older than a snapshot covering their key are already part of it
+  private stale(key: string, revision?: number): boolean {
+    if (revision == null) {
+      return false;
+    }
+    if (revision < this.revision) {
+      return true;
+    }
+    for (const scope in this.scope_revisions) {
+      if (in_scope(key, scope) && revision < this.scope_revisions[scope]) {
+        return true;
+      }
+    }
+    return false;
+  }
+
+  // applies a Set or Patch frame, returns false if nothing changed
+  private apply_update(message: WSMessage): boolean {
+    if (this.stale(message.key!, message.revision)) {
+      return false;
+    }
+    const key = message.key!;
+    if (message.message_type == WSMessageType.Set) {
+      // a Set held back by throttling may arrive after newer values
+      if (key in this.versions && message.version! <= this.versions[key]) {
+        return false;
+      }
+      this.receive(key, message.data, message.version!);
+      return true;
+    }
+    if (!(key in this.confirmed) || this.versions[key] != message.version! - 1) {
+      this.resync(key);
+      return false;
+    }
+    let value: any;
+    try {
+      value = apply_patch(copy(this.confirmed[key]), message.data);
+    } catch (error) {
+      console.error(error);
+      this.resync(key);
+      return false;
+    }
+    // drops local writes the server has not echoed yet, each comes back in its own frame
+    this.receive(key, value, message.version!);
+    return true;
+  }
+
+  private receive(key: string, value: any, version: number) {
+    this.confirmed[key] = value;
+    this.raw[key] = copy(value);
+    this.versions[key] = version;
+  }
+
+  private forget(key: string) {
+    delete this.raw[key];
+    delete this.confirmed[key];
+    delete this.versions[key];
+  }
+
+  private resync(key: string) {
+    this.send({
+      message_type: WSMessageType.Get,
+      id: this.message_id++,
+      key,
+    });
+  }
+
+  private async get_data(key: string): Promise<any> {
     const message: WSMessage = {
       message_type: WSMessageType.Get,
+      id: this.message_id++,
       key,
     };
 
-    if (this.state == ConnectionState.Up) {
-      this.ws?.send(JSON.stringify(message));
-    } else {
-      this.work_pool.push(key);
-    }
+    this.send(message);
 
     return new Promise((resolve) => {
       this.get_queue[key] = this.get_queue[key] || [];
@@ -94,19 +338,45 @@ export class Poca {
     });
   }
 
-  private async set_data(key: string, value: string) {
+  private async set_data(key: string, value: any, expected_version?: number) {
     const message: WSMessage = {
       message_type: WSMessageType.Set,
+      id: this.message_id++,
       key,
       data: value,
+      expected_version,
     };
-    this.ws?.send(JSON.stringify(message));
+    this.send(message);
+  }
+
+  version(key: string): number | undefined {
+    return this.versions[key];
+  }
+
+  // the items of a collection by item key, each item is synchronized as "{key}/{item}"
+  items<V>(key: string): {[item: string]: V} {
+    const prefix = key + "/";
+    const items: {[item: string]: V} = {};
+    for (const each in this.raw) {
+      if (each.startsWith(prefix)) {
+        items[each.slice(prefix.length)] = this.raw[each];
+      }
+    }
+    return items;
+  }
+
+  // the write is rejected if another writer got in since expected_version
+  update<T>(key: string, value: T, expected_version?: number) {
+    this.set_data(key, value, expected_version);
   }
 
   async reactive<T extends Object, K extends keyof T>(key: string): Promise<T> {
     const that = this;
-    const data = await this.get_data(key);
-    const value: T = JSON.parse(JSON.parse(data));
+    await this.synced;
+    const value: T =
+      key in this.raw
+        ? this.raw[key]
+        : await this.get_data(key);
     return new Promise((resolve) => {
       that.raw[key] = value;
       effect_callbacks[that.identifier][key] = [];
@@ -117,9 +387,10 @@ export class Poca {
           }
           return that.raw[key][prop as K];
         },
-        set(target, prop, value) {
-          target[prop as K] = value;
-          that.set_data(key, JSON.stringify(target));
+        set(_target, prop, value) {
+          // the target is replaced in `raw` by every update from the server
+          that.raw[key][prop as K] = value;
+          that.set_data(key, that.raw[key]);
           effect_callbacks[that.identifier][key].forEach((callback) =>
             callback()
           );
@@ -135,19 +406,19 @@ export class Poca {
     initial_value: T
   ): T {
     const that = this;
-    that.set_data(key, JSON.stringify(initial_value));
+    that.set_data(key, initial_value);
     that.raw[key] = initial_value;
     effect_callbacks[that.identifier][key] = [];
     const result = new Proxy(initial_value, {
-      get(target, prop) {
+      get(_target, prop) {
         if (setting_up_effect) {
           effect_callbacks[that.identifier][key].push(current_callback);
         }
-        return target[prop as K];
+        return that.raw[key][prop as K];
       },
-      set(target, prop, value) {
-        target[prop as K] = value;
-        that.set_data(key, JSON.stringify(target));
+      set(_target, prop, value) {
+        that.raw[key][prop as K] = value;
+        that.set_data(key, that.raw[key]);
         effect_callbacks[that.identifier][key].forEach((callback) =>
           callback()
         );
@@ -157,15 +428,353 @@ export class Poca {
     return result;
   }
 
-  emit(key: string) {
+  emit(key: string, payload?: any) {
     const message: WSMessage = {
       message_type: WSMessageType.Emit,
-      key
+      id: this.message_id++,
+      key,
+      data: payload,
+    };
+    this.send(message);
+  }
+
+  // listens to events emitted by the server, returns a function removing the listener
+  on<P>(key: string, listener: (payload: P) => void): () => void {
+    this.listeners[key] = this.listeners[key] || [];
+    this.listeners[key].push(listener);
+    return () => {
+      this.listeners[key] = this.listeners[key].filter((each) => each != listener);
+    };
+  }
+
+  // the current values of all keys in a scope, by full key
+  snapshot(scope: string): Promise<{[key: string]: {data: any; version: number}}> {
+    return this.request({
+      message_type: WSMessageType.Snapshot,
+      id: this.message_id++,
+      key: scope,
+    });
+  }
+
+  // once subscribed to any scope, only updates of subscribed scopes are received,
+  // resolves with the snapshot of the scope
+  subscribe(scope: string): Promise<{[key: string]: {data: any; version: number}}> {
+    this.subscriptions.push(scope);
+    return this.request({
+      message_type: WSMessageType.Subscribe,
+      id: this.message_id++,
+      key: scope,
+    });
+  }
+
+  unsubscribe(scope: string) {
+    this.subscriptions = this.subscriptions.filter((each) => each != scope);
+    this.send({
+      message_type: WSMessageType.Unsubscribe,
+      id: this.message_id++,
+      key: scope,
+    });
+  }
+
+  private request(message: WSMessage): Promise<any> {
+    return new Promise((resolve, reject) => {
+      this.pending_calls[message.id!] = {resolve, reject};
+      this.send(message);
+    });
+  }
+
+  // rejects with the WSError sent back by the server
+  call<Req, Resp>(key: string, request: Req): Promise<Resp> {
+    const message: WSMessage = {
+      message_type: WSMessageType.Call,
+      id: this.message_id++,
+      key,
+      data: request,
     };
-    this.ws?.send(JSON.stringify(message));
+    return this.request(message);
   }
 }
 
+// keys of a scope are the scope itself and everything below it
+function in_scope(key: string, scope: string): boolean {
+  if (!key.startsWith(scope)) {
+    return false;
+  }
+  const rest = key.slice(scope.length);
+  return rest == "" || rest.startsWith(".") || rest.startsWith("/");
+}
+
+interface PatchOperation {
+  op: string;
+  path: string;
+  value?: any;
+}
+
+// values are plain JSON, a deep copy keeps `raw` and `confirmed` apart
+function copy(value: any): any {
+  return value === undefined ? value : JSON.parse(JSON.stringify(value));
+}
+
+// applies the RFC 6902 operations produced by the server's diff
+function apply_patch(document: any, patch: PatchOperation[]): any {
+  for (const operation of patch) {
+    const path = operation.path
+      .split("/")
+      .slice(1)
+      .map((token) => token.replace(/~1/g, "/").replace(/~0/g, "~"));
+    if (path.length == 0) {
+      if (operation.op == "add" || operation.op == "replace") {
+        document = operation.value;
+        continue;
+      }
+      throw new Error(`Unsupported patch operation ${operation.op} on root`);
+    }
+    const parent = path
+      .slice(0, -1)
+      .reduce((node, token) => node[token], document);
+    const last = path[path.length - 1];
+    switch (operation.op) {
+      case "add":
+        if (Array.isArray(parent)) {
+          parent.splice(last == "-" ? parent.length : Number(last), 0, operation.value);
+        } else {
+          parent[last] = operation.value;
+        }
+        break;
+      case "remove":
+        if (Array.isArray(parent)) {
+          parent.splice(Number(last), 1);
+        } else {
+          delete parent[last];
+        }
+        break;
+      case "replace":
+        parent[last] = operation.value;
+        break;
+      default:
+        throw new Error(`Unsupported patch operation ${operation.op}`);
+    }
+  }
+  return document;
+}
+
+// the subset of MessagePack produced and accepted by the server's codec
+function encode_msgpack(value: any): Uint8Array {
+  const bytes: number[] = [];
+  // big-endian, value must fit into size bytes
+  const push_uint = (value: number, size: number) => {
+    for (let shift = (size - 1) * 8; shift >= 0; shift -= 8) {
+      bytes.push(Math.floor(value / Math.pow(2, shift)) & 0xff);
+    }
+  };
+  // lengths below fix_limit fit into the type byte,
+  // only str and bin have 8-bit lengths
+  const push_length = (
+    length: number,
+    fix: number,
+    fix_limit: number,
+    types: (number | null)[]
+  ) => {
+    if (length < fix_limit) {
+      bytes.push(fix | length);
+    } else if (types[0] != null && length < 0x100) {
+      bytes.push(types[0]);
+      push_uint(length, 1);
+    } else if (length < 0x10000) {
+      bytes.push(types[1]!);
+      push_uint(length, 2);
+    } else {
+      bytes.push(types[2]!);
+      push_uint(length, 4);
+    }
+  };
+  const write = (value: any) => {
+    if (value == null) {
+      bytes.push(0xc0);
+    } else if (typeof value == "boolean") {
+      bytes.push(value ? 0xc3 : 0xc2);
+    } else if (typeof value == "number") {
+      if (!Number.isSafeInteger(value)) {
+        const float = new DataView(new ArrayBuffer(8));
+        float.setFloat64(0, value);
+        bytes.push(0xcb);
+        new Uint8Array(float.buffer).forEach((byte) => bytes.push(byte));
+      } else if (value >= 0) {
+        if (value < 0x80) {
+          bytes.push(value);
+        } else if (value < 0x100) {
+          bytes.push(0xcc, value);
+        } else if (value < 0x10000) {
+          bytes.push(0xcd);
+          push_uint(value, 2);
+        } else if (value < 0x100000000) {
+          bytes.push(0xce);
+          push_uint(value, 4);
+        } else {
+          bytes.push(0xcf);
+          push_uint(value, 8);
+        }
+      } else if (value >= -0x20) {
+        bytes.push(value & 0xff);
+      } else if (value >= -0x80) {
+        bytes.push(0xd0, value & 0xff);
+      } else if (value >= -0x8000) {
+        bytes.push(0xd1);
+        push_uint(value + 0x10000, 2);
+      } else if (value >= -0x80000000) {
+        bytes.push(0xd2);
+        push_uint(value + 0x100000000, 4);
+      } else {
+        // two's complement of the high and low 32 bits
+        const high = Math.floor(value / 0x100000000);
+        bytes.push(0xd3);
+        push_uint(high + 0x100000000, 4);
+        push_uint(value - high * 0x100000000, 4);
+      }
+    } else if (typeof value == "string") {
+      const encoded = new TextEncoder().encode(value);
+      push_length(encoded.length, 0xa0, 0x20, [0xd9, 0xda, 0xdb]);
+      encoded.forEach((byte) => bytes.push(byte));
+    } else if (value instanceof Uint8Array) {
+      push_length(value.length, 0, 0, [0xc4, 0xc5, 0xc6]);
+      value.forEach((byte) => bytes.push(byte));
+    } else if (Array.isArray(value)) {
+      push_length(value.length, 0x90, 0x10, [null, 0xdc, 0xdd]);
+      value.forEach(write);
+    } else {
+      // like JSON.stringify, undefined fields are left out
+      const keys = Object.keys(value).filter((key) => value[key] !== undefined);
+      push_length(keys.length, 0x80, 0x10, [null, 0xde, 0xdf]);
+      for (const key of keys) {
+        write(key);
+        write(value[key]);
+      }
+    }
+  };
+  write(value);
+  return new Uint8Array(bytes);
+}
+
+function decode_msgpack(bytes: Uint8Array): any {
+  const view = new DataView(bytes.buffer, bytes.byteOffset, bytes.byteLength);
+  let offset = 0;
+  const uint = (size: number): number => {
+    let value = 0;
+    for (let index = 0; index < size; index++) {
+      value = value * 0x100 + view.getUint8(offset++);
+    }
+    return value;
+  };
+  const int = (size: number): number => {
+    if (size == 8) {
+      // the high word carries the sign, the sum is exact for safe integers
+      const high = int(4);
+      return high * 0x100000000 + uint(4);
+    }
+    const value = uint(size);
+    return value >= Math.pow(2, size * 8 - 1)
+      ? value - Math.pow(2, size * 8)
+      : value;
+  };
+  const float = (size: number): number => {
+    const value = size == 4 ? view.getFloat32(offset) : view.getFloat64(offset);
+    offset += size;
+    return value;
+  };
+  const str = (length: number): string => {
+    const value = new TextDecoder().decode(
+      bytes.subarray(offset, offset + length)
+    );
+    offset += length;
+    return value;
+  };
+  const bin = (length: number): Uint8Array => {
+    const value = bytes.slice(offset, offset + length);
+    offset += length;
+    return value;
+  };
+  const array = (length: number): any[] => {
+    const value: any[] = [];
+    for (let index = 0; index < length; index++) {
+      value.push(read());
+    }
+    return value;
+  };
+  const map = (length: number): {[key: string]: any} => {
+    const value: {[key: string]: any} = {};
+    for (let index = 0; index < length; index++) {
+      const key = read();
+      value[key] = read();
+    }
+    return value;
+  };
+  const read = (): any => {
+    const type = view.getUint8(offset++);
+    if (type < 0x80) {
+      return type;
+    } else if (type < 0x90) {
+      return map(type & 0x0f);
+    } else if (type < 0xa0) {
+      return array(type & 0x0f);
+    } else if (type < 0xc0) {
+      return str(type & 0x1f);
+    } else if (type >= 0xe0) {
+      return type - 0x100;
+    }
+    switch (type) {
+      case 0xc0:
+        return null;
+      case 0xc2:
+        return false;
+      case 0xc3:
+        return true;
+      case 0xc4:
+        return bin(uint(1));
+      case 0xc5:
+        return bin(uint(2));
+      case 0xc6:
+        return bin(uint(4));
+      case 0xca:
+        return float(4);
+      case 0xcb:
+        return float(8);
+      case 0xcc:
+        return uint(1);
+      case 0xcd:
+        return uint(2);
+      case 0xce:
+        return uint(4);
+      case 0xcf:
+        return uint(8);
+      case 0xd0:
+        return int(1);
+      case 0xd1:
+        return int(2);
+      case 0xd2:
+        return int(4);
+      case 0xd3:
+        return int(8);
+      case 0xd9:
+        return str(uint(1));
+      case 0xda:
+        return str(uint(2));
+      case 0xdb:
+        return str(uint(4));
+      case 0xdc:
+        return array(uint(2));
+      case 0xdd:
+        return array(uint(4));
+      case 0xde:
+        return map(uint(2));
+      case 0xdf:
+        return map(uint(4));
+      default:
+        throw new Error(`Unsupported MessagePack type 0x${type.toString(16)}`);
+    }
+  };
+  return read();
+}
+
 let setting_up_effect = false;
 let current_callback = () => {};
 
@@ -0,0 +1,713 @@
+const O=function(){const e=document.createElement("link").relList;if(e&&e.supports&&e.supports("modulepreload"))return;for(const s of document.querySelectorAll('link[rel="modulepreload"]'))n(s);new MutationObserver(s=>{for(const t of s)if(t.type==="childList")for(const o of t.addedNodes)o.tagName==="LINK"&&o.rel==="modulepreload"&&n(o)}).observe(document,{childList:!0,subtree:!0});function i(s){const t={};return s.integrity&&(t.integrity=s.integrity),s.referrerpolicy&&(t.referrerPolicy=s.referrerpolicy),s.crossorigin==="use-credentials"?t.credentials="include":s.crossorigin==="anonymous"?t.credentials="omit":t.credentials="same-origin",t}function n(s){if(s.ep)return;s.ep=!0;const t=i(s);fetch(s.href,t)}};O();
+var __awaiter = (this && this.__awaiter) || function (thisArg, _arguments, P, generator) {
+function adopt(value) { return value instanceof P ? value : new P(function (resolve) { resolve(value); }); }
+return new (P || (P = Promise))(function (resolve, reject) {
+function fulfilled(value) { try { step(generator.next(value)); } catch (e) { reject(e); } }
+function rejected(value) { try { step(generator["throw"](value)); } catch (e) { reject(e); } }
+function step(result) { result.done ? resolve(result.value) : adopt(result.value).then(fulfilled, rejected); }
+step((generator = generator.apply(thisArg, _arguments || [])).next());
+});
+};
+var WSMessageType;
+(function (WSMessageType) {
+WSMessageType[WSMessageType["Set"] = 1] = "Set";
+WSMessageType[WSMessageType["Emit"] = 2] = "Emit";
+WSMessageType[WSMessageType["Get"] = 3] = "Get";
+WSMessageType[WSMessageType["Error"] = 4] = "Error";
+WSMessageType[WSMessageType["Hello"] = 5] = "Hello";
+WSMessageType[WSMessageType["Welcome"] = 6] = "Welcome";
+WSMessageType[WSMessageType["Snapshot"] = 7] = "Snapshot";
+WSMessageType[WSMessageType["Patch"] = 8] = "Patch";
+WSMessageType[WSMessageType["Call"] = 9] = "Call";
+WSMessageType[WSMessageType["Reply"] = 10] = "Reply";
+WSMessageType[WSMessageType["Batch"] = 11] = "Batch";
+WSMessageType[WSMessageType["Removed"] = 12] = "Removed";
+WSMessageType[WSMessageType["Subscribe"] = 13] = "Subscribe";
+WSMessageType[WSMessageType["Unsubscribe"] = 14] = "Unsubscribe";
+})(WSMessageType || (WSMessageType = {}));
+const PROTOCOL_VERSION = 1;
+const SUPPORTED_ENCODINGS = ["msgpack", "json"];
+var ConnectionState;
+(function (ConnectionState) {
+ConnectionState[ConnectionState["Up"] = 0] = "Up";
+ConnectionState[ConnectionState["Down"] = 1] = "Down";
+})(ConnectionState || (ConnectionState = {}));
+class Poca {
+constructor(addr) {
+this.addr = addr;
+this.raw = {};
+this.confirmed = {};
+this.versions = {};
+this.outbox = [];
+this.message_id = 0;
+this.revision = -1;
+this.scope_revisions = {};
+this.subscriptions = [];
+this.get_queue = {};
+this.listeners = {};
+this.pending_calls = {};
+this.state = ConnectionState.Down;
+this.identifier = Symbol();
+effect_callbacks[this.identifier] = {};
+this.synced = new Promise((resolve) => (this.mark_synced = resolve));
+}
+connect() {
+return __awaiter(this, void 0, void 0, function* () {
+let that = this;
+new Promise((resolve) => {
+var _a;
+(_a = that.ws) === null || _a === void 0 ? void 0 : _a.close();
+that.session = undefined;
+that.subscriptions = [];
+that.ws = new WebSocket("ws://" + this.addr);
+that.ws.binaryType = "arraybuffer";
+that.ws.onopen = () => {
+that.ws.onmessage = (event) => {
+var _a, _b, _c, _d, _e, _f, _g, _h, _j, _k, _l, _m;
+const message = that.decode(event.data);
+switch (message.message_type) {
+case WSMessageType.Welcome:
+that.session = message.data;
+that.state = ConnectionState.Up;
+that.outbox.forEach((message) => that.ws.send(that.encode(message)));
+that.outbox = [];
+break;
+case WSMessageType.Snapshot:
+const values = message.data;
+const scopes = message.key != null ? [message.key] : that.subscriptions;
+for (const key in that.raw) {
+if (!(key in values) &&
+(scopes.length == 0 || scopes.some((scope) => in_scope(key, scope)))) {
+that.forget(key);
+}
+}
+for (const key in values) {
+that.receive(key, values[key].data, values[key].version);
+(_a = effect_callbacks[that.identifier][key]) === null || _a === void 0 ? void 0 : _a.forEach((callback) => callback());
+}
+if (message.key != null) {
+that.scope_revisions[message.key] = message.revision;
+(_b = that.pending_calls[message.id]) === null || _b === void 0 ? void 0 : _b.resolve(values);
+delete that.pending_calls[message.id];
+break;
+}
+that.revision = message.revision;
+that.mark_synced();
+resolve(undefined);
+break;
+case WSMessageType.Get:
+if (((_c = this.get_queue[message.key]) === null || _c === void 0 ? void 0 : _c.length) > 0) {
+that.receive(message.key, message.data, message.version);
+(_d = this.get_queue[message.key].shift()) === null || _d === void 0 ? void 0 : _d(that.raw[message.key]);
+}
+else if (!(message.key in that.versions) ||
+message.version >= that.versions[message.key]) {
+that.receive(message.key, message.data, message.version);
+(_e = effect_callbacks[that.identifier][message.key]) === null || _e === void 0 ? void 0 : _e.forEach((callback) => callback());
+}
+break;
+case WSMessageType.Patch:
+case WSMessageType.Set:
+if (that.apply_update(message)) {
+(_f = effect_callbacks[that.identifier][message.key]) === null || _f === void 0 ? void 0 : _f.forEach((callback) => callback());
+}
+break;
+case WSMessageType.Batch: {
+const updates = message.data;
+const changed = new Set();
+for (const update of updates) {
+if (update.message_type == WSMessageType.Error) {
+console.error(`Error ${(_g = update.error) === null || _g === void 0 ? void 0 : _g.code} on key ${update.key}: ${(_h = update.error) === null || _h === void 0 ? void 0 : _h.message}`);
+}
+else if (that.apply_update(update)) {
+changed.add(update.key);
+}
+}
+changed.forEach((key) => { var _a; return (_a = effect_callbacks[that.identifier][key]) === null || _a === void 0 ? void 0 : _a.forEach((callback) => callback()); });
+break;
+}
+case WSMessageType.Removed:
+if (that.stale(message.key, message.revision)) {
+break;
+}
+that.forget(message.key);
+break;
+case WSMessageType.Emit:
+(_j = that.listeners[message.key]) === null || _j === void 0 ? void 0 : _j.forEach((listener) => listener(message.data));
+break;
+case WSMessageType.Reply:
+(_k = that.pending_calls[message.id]) === null || _k === void 0 ? void 0 : _k.resolve(message.data);
+delete that.pending_calls[message.id];
+break;
+case WSMessageType.Error:
+if (message.id != null && message.id in that.pending_calls) {
+that.pending_calls[message.id].reject(message.error);
+delete that.pending_calls[message.id];
+break;
+}
+console.error(`Error ${(_l = message.error) === null || _l === void 0 ? void 0 : _l.code} on key ${message.key} (message ${message.id}): ${(_m = message.error) === null || _m === void 0 ? void 0 : _m.message}`);
+break;
+default:
+console.log("Unimplemented message: " + message);
+}
+};
+const hello = {
+message_type: WSMessageType.Hello,
+id: that.message_id++,
+data: {
+version: PROTOCOL_VERSION,
+encodings: SUPPORTED_ENCODINGS,
+},
+};
+that.ws.send(that.encode(hello));
+};
+});
+});
+}
+encode(message) {
+var _a;
+if (((_a = this.session) === null || _a === void 0 ? void 0 : _a.encoding) == "msgpack") {
+return encode_msgpack(message);
+}
+return new TextEncoder().encode(JSON.stringify(message));
+}
+decode(data) {
+var _a;
+if (typeof data == "string") {
+return JSON.parse(data);
+}
+const bytes = new Uint8Array(data);
+if (((_a = this.session) === null || _a === void 0 ? void 0 : _a.encoding) == "msgpack") {
+return decode_msgpack(bytes);
+}
+return JSON.parse(new TextDecoder().decode(bytes));
+}
+close() {
+var _a;
+(_a = this.ws) === null || _a === void 0 ? void 0 : _a.close();
+this.state = ConnectionState.Down;
+}
+send(message) {
+var _a;
+if (this.state == ConnectionState.Up) {
+(_a = this.ws) === null || _a === void 0 ? void 0 : _a.send(this.encode(message));
+}
+else {
+this.outbox.push(message);
+}
+}
+stale(key, revision) {
+if (revision == null) {
+return false;
+}
+if (revision < this.revision) {
+return true;
+}
+for (const scope in this.scope_revisions) {
+if (in_scope(key, scope) && revision < this.scope_revisions[scope]) {
+return true;
+}
+}
+return false;
+}
+apply_update(message) {
+if (this.stale(message.key, message.revision)) {
+return false;
+}
+const key = message.key;
+if (message.message_type == WSMessageType.Set) {
+if (key in this.versions && message.version <= this.versions[key]) {
+return false;
+}
+this.receive(key, message.data, message.version);
+return true;
+}
+if (!(key in this.confirmed) || this.versions[key] != message.version - 1) {
+this.resync(key);
+return false;
+}
+let value;
+try {
+value = apply_patch(copy(this.confirmed[key]), message.data);
+}
+catch (error) {
+console.error(error);
+this.resync(key);
+return false;
+}
+this.receive(key, value, message.version);
+return true;
+}
+receive(key, value, version) {
+this.confirmed[key] = value;
+this.raw[key] = copy(value);
+this.versions[key] = version;
+}
+forget(key) {
+delete this.raw[key];
+delete this.confirmed[key];
+delete this.versions[key];
+}
+resync(key) {
+this.send({
+message_type: WSMessageType.Get,
+id: this.message_id++,
+key,
+});
+}
+get_data(key) {
+return __awaiter(this, void 0, void 0, function* () {
+const message = {
+message_type: WSMessageType.Get,
+id: this.message_id++,
+key,
+};
+this.send(message);
+return new Promise((resolve) => {
+this.get_queue[key] = this.get_queue[key] || [];
+this.get_queue[key].push(resolve);
+});
+});
+}
+set_data(key, value, expected_version) {
+return __awaiter(this, void 0, void 0, function* () {
+const message = {
+message_type: WSMessageType.Set,
+id: this.message_id++,
+key,
+data: value,
+expected_version,
+};
+this.send(message);
+});
+}
+version(key) {
+return this.versions[key];
+}
+items(key) {
+const prefix = key + "/";
+const items = {};
+for (const each in this.raw) {
+if (each.startsWith(prefix)) {
+items[each.slice(prefix.length)] = this.raw[each];
+}
+}
+return items;
+}
+update(key, value, expected_version) {
+this.set_data(key, value, expected_version);
+}
+reactive(key) {
+return __awaiter(this, void 0, void 0, function* () {
+const that = this;
+yield this.synced;
+const value = key in this.raw
+? this.raw[key]
+: yield this.get_data(key);
+return new Promise((resolve) => {
+that.raw[key] = value;
+effect_callbacks[that.identifier][key] = [];
+const result = new Proxy(value, {
+get(_target, prop) {
+if (setting_up_effect) {
+effect_callbacks[that.identifier][key].push(current_callback);
+}
+return that.raw[key][prop];
+},
+set(_target, prop, value) {
+that.raw[key][prop] = value;
+that.set_data(key, that.raw[key]);
+effect_callbacks[that.identifier][key].forEach((callback) => callback());
+return true;
+},
+});
+resolve(result);
+});
+});
+}
+reactive_with_default(key, initial_value) {
+const that = this;
+that.set_data(key, initial_value);
+that.raw[key] = initial_value;
+effect_callbacks[that.identifier][key] = [];
+const result = new Proxy(initial_value, {
+get(_target, prop) {
+if (setting_up_effect) {
+effect_callbacks[that.identifier][key].push(current_callback);
+}
+return that.raw[key][prop];
+},
+set(_target, prop, value) {
+that.raw[key][prop] = value;
+that.set_data(key, that.raw[key]);
+effect_callbacks[that.identifier][key].forEach((callback) => callback());
+return true;
+},
+});
+return result;
+}
+emit(key, payload) {
+const message = {
+message_type: WSMessageType.Emit,
+id: this.message_id++,
+key,
+data: payload,
+};
+this.send(message);
+}
+on(key, listener) {
+this.listeners[key] = this.listeners[key] || [];
+this.listeners[key].push(listener);
+return () => {
+this.listeners[key] = this.listeners[key].filter((each) => each != listener);
+};
+}
+snapshot(scope) {
+return this.request({
+message_type: WSMessageType.Snapshot,
+id: this.message_id++,
+key: scope,
+});
+}
+subscribe(scope) {
+this.subscriptions.push(scope);
+return this.request({
+message_type: WSMessageType.Subscribe,
+id: this.message_id++,
+key: scope,
+});
+}
+unsubscribe(scope) {
+this.subscriptions = this.subscriptions.filter((each) => each != scope);
+this.send({
+message_type: WSMessageType.Unsubscribe,
+id: this.message_id++,
+key: scope,
+});
+}
+request(message) {
+return new Promise((resolve, reject) => {
+this.pending_calls[message.id] = {resolve, reject};
+this.send(message);
+});
+}
+call(key, request) {
+const message = {
+message_type: WSMessageType.Call,
+id: this.message_id++,
+key,
+data: request,
+};
+return this.request(message);
+}
+}
+function in_scope(key, scope) {
+if (!key.startsWith(scope)) {
+return false;
+}
+const rest = key.slice(scope.length);
+return rest == "" || rest.startsWith(".") || rest.startsWith("/");
+}
+function copy(value) {
+return value === undefined ? value : JSON.parse(JSON.stringify(value));
+}
+function apply_patch(document, patch) {
+for (const operation of patch) {
+const path = operation.path
+.split("/")
+.slice(1)
+.map((token) => token.replace(/~1/g, "/").replace(/~0/g, "~"));
+if (path.length == 0) {
+if (operation.op == "add" || operation.op == "replace") {
+document = operation.value;
+continue;
+}
+throw new Error(`Unsupported patch operation ${operation.op} on root`);
+}
+const parent = path
+.slice(0, -1)
+.reduce((node, token) => node[token], document);
+const last = path[path.length - 1];
+switch (operation.op) {
+case "add":
+if (Array.isArray(parent)) {
+parent.splice(last == "-" ? parent.length : Number(last), 0, operation.value);
+}
+else {
+parent[last] = operation.value;
+}
+break;
+case "remove":
+if (Array.isArray(parent)) {
+parent.splice(Number(last), 1);
+}
+else {
+delete parent[last];
+}
+break;
+case "replace":
+parent[last] = operation.value;
+break;
+default:
+throw new Error(`Unsupported patch operation ${operation.op}`);
+}
+}
+return document;
+}
+function encode_msgpack(value) {
+const bytes = [];
+const push_uint = (value, size) => {
+for (let shift = (size - 1) * 8; shift >= 0; shift -= 8) {
+bytes.push(Math.floor(value / Math.pow(2, shift)) & 0xff);
+}
+};
+const push_length = (
+length,
+fix,
+fix_limit,
+types
+) => {
+if (length < fix_limit) {
+bytes.push(fix | length);
+}
+else if (types[0] != null && length < 0x100) {
+bytes.push(types[0]);
+push_uint(length, 1);
+}
+else if (length < 0x10000) {
+bytes.push(types[1]);
+push_uint(length, 2);
+}
+else {
+bytes.push(types[2]);
+push_uint(length, 4);
+}
+};
+const write = (value) => {
+if (value == null) {
+bytes.push(0xc0);
+}
+else if (typeof value == "boolean") {
+bytes.push(value ? 0xc3 : 0xc2);
+}
+else if (typeof value == "number") {
+if (!Number.isSafeInteger(value)) {
+const float = new DataView(new ArrayBuffer(8));
+float.setFloat64(0, value);
+bytes.push(0xcb);
+new Uint8Array(float.buffer).forEach((byte) => bytes.push(byte));
+}
+else if (value >= 0) {
+if (value < 0x80) {
+bytes.push(value);
+}
+else if (value < 0x100) {
+bytes.push(0xcc, value);
+}
+else if (value < 0x10000) {
+bytes.push(0xcd);
+push_uint(value, 2);
+}
+else if (value < 0x100000000) {
+bytes.push(0xce);
+push_uint(value, 4);
+}
+else {
+bytes.push(0xcf);
+push_uint(value, 8);
+}
+}
+else if (value >= -0x20) {
+bytes.push(value & 0xff);
+}
+else if (value >= -0x80) {
+bytes.push(0xd0, value & 0xff);
+}
+else if (value >= -0x8000) {
+bytes.push(0xd1);
+push_uint(value + 0x10000, 2);
+}
+else if (value >= -0x80000000) {
+bytes.push(0xd2);
+push_uint(value + 0x100000000, 4);
+}
+else {
+const high = Math.floor(value / 0x100000000);
+bytes.push(0xd3);
+push_uint(high + 0x100000000, 4);
+push_uint(value - high * 0x100000000, 4);
+}
+}
+else if (typeof value == "string") {
+const encoded = new TextEncoder().encode(value);
+push_length(encoded.length, 0xa0, 0x20, [0xd9, 0xda, 0xdb]);
+encoded.forEach((byte) => bytes.push(byte));
+}
+else if (value instanceof Uint8Array) {
+push_length(value.length, 0, 0, [0xc4, 0xc5, 0xc6]);
+value.forEach((byte) => bytes.push(byte));
+}
+else if (Array.isArray(value)) {
+push_length(value.length, 0x90, 0x10, [null, 0xdc, 0xdd]);
+value.forEach(write);
+}
+else {
+const keys = Object.keys(value).filter((key) => value[key] !== undefined);
+push_length(keys.length, 0x80, 0x10, [null, 0xde, 0xdf]);
+for (const key of keys) {
+write(key);
+write(value[key]);
+}
+}
+};
+write(value);
+return new Uint8Array(bytes);
+}
+function decode_msgpack(bytes) {
+const view = new DataView(bytes.buffer, bytes.byteOffset, bytes.byteLength);
+let offset = 0;
+const uint = (size) => {
+let value = 0;
+for (let index = 0; index < size; index++) {
+value = value * 0x100 + view.getUint8(offset++);
+}
+return value;
+};
+const int = (size) => {
+if (size == 8) {
+const high = int(4);
+return high * 0x100000000 + uint(4);
+}
+const value = uint(size);
+return value >= Math.pow(2, size * 8 - 1)
+? value - Math.pow(2, size * 8)
+: value;
+};
+const float = (size) => {
+const value = size == 4 ? view.getFloat32(offset) : view.getFloat64(offset);
+offset += size;
+return value;
+};
+const str = (length) => {
+const value = new TextDecoder().decode(
+bytes.subarray(offset, offset + length)
+);
+offset += length;
+return value;
+};
+const bin = (length) => {
+const value = bytes.slice(offset, offset + length);
+offset += length;
+return value;
+};
+const array = (length) => {
+const value = [];
+for (let index = 0; index < length; index++) {
+value.push(read());
+}
+return value;
+};
+const map = (length) => {
+const value = {};
+for (let index = 0; index < length; index++) {
+const key = read();
+value[key] = read();
+}
+return value;
+};
+const read = () => {
+const type = view.getUint8(offset++);
+if (type < 0x80) {
+return type;
+}
+else if (type < 0x90) {
+return map(type & 0x0f);
+}
+else if (type < 0xa0) {
+return array(type & 0x0f);
+}
+else if (type < 0xc0) {
+return str(type & 0x1f);
+}
+else if (type >= 0xe0) {
+return type - 0x100;
+}
+switch (type) {
+case 0xc0:
+return null;
+case 0xc2:
+return false;
+case 0xc3:
+return true;
+case 0xc4:
+return bin(uint(1));
+case 0xc5:
+return bin(uint(2));
+case 0xc6:
+return bin(uint(4));
+case 0xca:
+return float(4);
+case 0xcb:
+return float(8);
+case 0xcc:
+return uint(1);
+case 0xcd:
+return uint(2);
+case 0xce:
+return uint(4);
+case 0xcf:
+return uint(8);
+case 0xd0:
+return int(1);
+case 0xd1:
+return int(2);
+case 0xd2:
+return int(4);
+case 0xd3:
+return int(8);
+case 0xd9:
+return str(uint(1));
+case 0xda:
+return str(uint(2));
+case 0xdb:
+return str(uint(4));
+case 0xdc:
+return array(uint(2));
+case 0xdd:
+return array(uint(4));
+case 0xde:
+return map(uint(2));
+case 0xdf:
+return map(uint(4));
+default:
+throw new Error(`Unsupported MessagePack type 0x${type.toString(16)}`);
+}
+};
+return read();
+}
+let setting_up_effect = false;
+let current_callback = () => { };
+let effect_callbacks = {};
+function effect(inner) {
+setting_up_effect = true;
+current_callback = inner;
+inner();
+setting_up_effect = false;
+}
+const POCA = new Poca("localhost:2341");
+await POCA.connect();
+const GUESS = await POCA.reactive("guess");
+const ANSWER = await POCA.reactive("answer");
+const input_field = document.querySelector("#input");
+input_field.addEventListener("input", () => {
+GUESS.guess = input_field.value;
+});
+const close_button = document.querySelector("#close");
+close_button.addEventListener("click", () => {
+POCA.emit("close");
+});
+effect(() => {
+const result_field = document.querySelector("#result");
+console.log(ANSWER.answer);
+result_field.innerHTML = ANSWER.answer;
+});
@@ -1,43 +1,46 @@
-use poca::{include_app_dir, DataHandle, Poca, WindowOptions};
+use poca::{include_app_dir, Poca, WindowOptions};
 use rand::Rng;
 
-lazy_static::lazy_static! {
-    static ref POCA: Poca = Poca::new("localhost:2341", include_app_dir!("frontend/dist/"),WindowOptions::new("My Guessing Game",(600,600),false));
-    static ref GUESS: DataHandle<Guess> = POCA.data("guess", Guess{ guess:"101".to_string()});
-    static ref ANSWER: DataHandle<Answer> = POCA.data("answer", Answer { answer: "Input something!".to_string()});
-}
-
 use ts2rs::import;
 
 import!("frontend/src/interface.ts");
 
 #[tokio::main]
 async fn main() {
-    lazy_static::initialize(&ANSWER);
-    let target = rand::thread_rng().gen_range(0..100);
-    GUESS.on_change(move |new_guess| match new_guess.guess.parse::<i32>() {
-        Ok(guess) => {
-            if guess == target {
-                ANSWER.set(Answer {
-                    answer: "You win!".to_string(),
-                });
-            } else if guess > target {
-                ANSWER.set(Answer {
-                    answer: "Too high!".to_string(),
-                });
-            } else {
-                ANSWER.set(Answer {
-                    answer: "Too low!".to_string(),
-                });
+    let poca = Poca::new(
+        "localhost:2341",
+        include_app_dir!("frontend/dist/"),
+        WindowOptions::new("My Guessing Game", (600, 600), false),
+    );
+    let guess = poca
+        .data(
+            "guess",
+            Guess {
+                guess: "101".to_string(),
+            },
+        )
+        .validate(|new_guess, _| match new_guess.guess.parse::<i32>() {
+            Ok(guess) if !(0..100).contains(&guess) => {
+                Err("Guess should be between 0 and 99".to_string())
             }
-        }
-        Err(_) => {
-            ANSWER.set(Answer {
-                answer: "Input should be a number!".to_string(),
-            });
+            _ => Ok(()),
+        });
+    let target = rand::thread_rng().gen_range(0..100);
+    let _answer = poca.computed("answer", &guess, move |guess| {
+        let answer = match guess.guess.parse::<i32>() {
+            // the initial guess is out of range
+            Ok(guess) if !(0..100).contains(&guess) => "Input something!",
+            Ok(guess) if guess == target => "You win!",
+            Ok(guess) if guess > target => "Too high!",
+            Ok(_) => "Too low!",
+            Err(_) => "Input should be a number!",
+        };
+        Answer {
+            answer: answer.to_string(),
         }
     });
-    POCA.event("close", || POCA.stop());
-    POCA.start().await;
-    POCA.show_window();
+    let closer = poca.clone();
+    poca.event("close", move |()| closer.stop()).detach();
+    poca.start().await;
+    poca.show_window();
 }
@@ -0,0 +1,167 @@
+use std::fmt::{self, Display};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+// wire encodings a connection can negotiate during the handshake,
+// frames of every encoding travel as binary WebSocket frames
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    Json,
+    MessagePack,
+}
+
+pub const SUPPORTED_CODECS: &[Codec] = &[Codec::Json, Codec::MessagePack];
+
+#[derive(Debug)]
+pub enum CodecError {
+    Json(serde_json::Error),
+    MessagePack(rmp_serde::decode::Error),
+}
+
+impl Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodecError::Json(error) => write!(f, "{}", error),
+            CodecError::MessagePack(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl Codec {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Codec::Json => "json",
+            Codec::MessagePack => "msgpack",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Codec> {
+        SUPPORTED_CODECS
+            .iter()
+            .copied()
+            .find(|codec| codec.name() == name)
+    }
+
+    pub fn encode<T: Serialize + ?Sized>(&self, value: &T) -> Vec<u8> {
+        match self {
+            Codec::Json => serde_json::to_vec(value).unwrap(),
+            // named so that structs arrive as maps on the client
+            Codec::MessagePack => rmp_serde::to_vec_named(value).unwrap(),
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T, CodecError> {
+        match self {
+            Codec::Json => serde_json::from_slice(data).map_err(CodecError::Json),
+            Codec::MessagePack => rmp_serde::from_slice(data).map_err(CodecError::MessagePack),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::message::{ErrorCode, WSError, WSMessage, WSMessageType};
+
+    #[test]
+    fn round_trip() {
+        let message = WSMessage {
+            id: Some(7),
+            key: Some("settings.values".to_string()),
+            data: Some(json!({
+                "name": "poca",
+                "values": [0, 1, -1, 255, -129, 70000, 4294967296u64, 0.5],
+                "nested": { "empty": [], "missing": null, "flag": true },
+            })),
+            revision: Some(u64::MAX),
+            version: Some(3),
+            expected_version: Some(2),
+            error: Some(WSError::new(ErrorCode::VersionConflict, "conflict")),
+            ..WSMessage::new(WSMessageType::Set)
+        };
+        for codec in SUPPORTED_CODECS {
+            let decoded: WSMessage = codec.decode(&codec.encode(&message)).unwrap();
+            assert_eq!(
+                serde_json::to_value(&decoded).unwrap(),
+                serde_json::to_value(&message).unwrap(),
+                "{} round trip",
+                codec.name()
+            );
+        }
+    }
+
+    // the client's tests decode and encode the same bytes, see client/src/index.test.ts
+    const SNAPSHOT_FRAME: &str = concat!(
+        "83ac6d6573736167655f7479706507a46461746182a76e756d6265727382a464617461de0010a466",
+        "6c6167c3a5666c6f6174cb3fe0000000000000a3693136d1fc18a3693332d2fffe7960a3693634d3",
+        "ffffff0000000000a26938d09ca46c697374dc0010000102030405060708090a0b0c0d0e0fa96c6f",
+        "6e675f74657874d92861616161616161616161616161616161616161616161616161616161616161",
+        "616161616161616161a76d697373696e67c0a86e65676174697665fba66e657374656482a5656d70",
+        "747990a36d617080a474657874a668c3a96c6c6fa3753136cdea60a3753332ce00011170a3753634",
+        "cf0000010000000000a27538ccc8a776657273696f6e02a4746f646f82a46461746182a4646f6e65",
+        "c2a57469746c65a46d696c6ba776657273696f6e00a87265766973696f6e03",
+    );
+    const SET_FRAME: &str = concat!(
+        "84ac6d6573736167655f7479706501a2696401a36b6579a4746f646fa46461746182a4646f6e65c3",
+        "a57469746c65a46d696c6b",
+    );
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    #[test]
+    fn client_frames() {
+        let snapshot = WSMessage {
+            revision: Some(3),
+            data: Some(json!({
+                "numbers": {
+                    "data": {
+                        "u8": 200,
+                        "u16": 60000,
+                        "u32": 70000,
+                        "u64": 1099511627776u64,
+                        "negative": -5,
+                        "i8": -100,
+                        "i16": -1000,
+                        "i32": -100000,
+                        "i64": -1099511627776i64,
+                        "float": 0.5,
+                        "text": "héllo",
+                        "long_text": "a".repeat(40),
+                        "list": (0..16).collect::<Vec<u8>>(),
+                        "missing": null,
+                        "flag": true,
+                        "nested": { "empty": [], "map": {} },
+                    },
+                    "version": 2,
+                },
+                "todo": { "data": { "done": false, "title": "milk" }, "version": 0 },
+            })),
+            ..WSMessage::new(WSMessageType::Snapshot)
+        };
+        assert_eq!(hex(&Codec::MessagePack.encode(&snapshot)), SNAPSHOT_FRAME);
+
+        let set = WSMessage {
+            id: Some(1),
+            key: Some("todo".to_string()),
+            data: Some(json!({ "done": true, "title": "milk" })),
+            ..WSMessage::new(WSMessageType::Set)
+        };
+        assert_eq!(hex(&Codec::MessagePack.encode(&set)), SET_FRAME);
+        let decoded: WSMessage = Codec::MessagePack
+            .decode(&Codec::MessagePack.encode(&set))
+            .unwrap();
+        assert_eq!(decoded.data, set.data);
+        assert!(decoded.expected_version.is_none());
+    }
+
+    #[test]
+    fn malformed_frames() {
+        for codec in SUPPORTED_CODECS {
+            assert!(codec.decode::<WSMessage>(&[0xc1, 0xff]).is_err());
+        }
+    }
+}
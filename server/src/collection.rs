@@ -0,0 +1,186 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    hash::Hash,
+    sync::Arc,
+};
+
+use parking_lot::{Mutex, MutexGuard};
+
+use crate::{
+    data_handle::DataHandle,
+    event_handler::Subscription,
+    poca::{insert_element, remove_element, set_access, Access, BroadcastSender, Revision, Store},
+    synchronizable::Synchronizable,
+};
+
+// keys of the collections that have a handle
+pub(crate) type Collections = Arc<Mutex<HashSet<String>>>;
+
+// keeps the key of a collection taken until the last clone of its handle is dropped
+struct Reservation {
+    key: String,
+    collections: Collections,
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.collections.lock().remove(&self.key);
+    }
+}
+
+// every item is its own element with the key "{collection}/{item}", so items are
+// sent, versioned and written by clients one at a time
+#[derive(Clone)]
+pub struct CollectionHandle<K, V>
+where
+    K: Display + Eq + Hash + Clone + Send + Sync + 'static,
+    V: Synchronizable,
+{
+    key: String,
+    store: Store,
+    revision: Revision,
+    sender: BroadcastSender,
+    access: Access,
+    items: Arc<Mutex<HashMap<K, DataHandle<V>>>>,
+    _reservation: Arc<Reservation>,
+}
+
+impl<K, V> CollectionHandle<K, V>
+where
+    K: Display + Eq + Hash + Clone + Send + Sync + 'static,
+    V: Synchronizable,
+{
+    // panics if there already is a collection with this key
+    pub(crate) fn new(
+        key: String,
+        store: Store,
+        revision: Revision,
+        sender: BroadcastSender,
+        collections: Collections,
+    ) -> Self {
+        if !collections.lock().insert(key.clone()) {
+            panic!("Collection {} already exists", key);
+        }
+        let reservation = Reservation {
+            key: key.clone(),
+            collections,
+        };
+        Self {
+            key,
+            store,
+            revision,
+            sender,
+            access: Access::ReadWrite,
+            items: Arc::new(Mutex::new(HashMap::new())),
+            _reservation: Arc::new(reservation),
+        }
+    }
+
+    // items removed by key, e.g. with `Poca::remove_data`, are dropped first
+    fn items(&self) -> MutexGuard<'_, HashMap<K, DataHandle<V>>> {
+        let mut items = self.items.lock();
+        items.retain(|_, handle| !handle.data_element().read().removed);
+        items
+    }
+
+    // applies to all items, including the ones inserted later
+    pub fn access(mut self, access: Access) -> Self {
+        self.access = access;
+        for handle in self.items().values() {
+            set_access(
+                &self.revision,
+                &self.sender,
+                handle.get_key(),
+                &mut handle.data_element().write(),
+                access,
+            );
+        }
+        self
+    }
+
+    pub fn get_key(&self) -> &str {
+        &self.key
+    }
+
+    pub fn item_key(&self, item: &K) -> String {
+        format!("{}/{}", self.key, item)
+    }
+
+    // updates the item if it already exists
+    pub fn insert(&self, item: K, value: V) {
+        let mut items = self.items();
+        if let Some(handle) = items.get(&item).cloned() {
+            // on_change handlers may use the collection
+            drop(items);
+            handle.set(value);
+            return;
+        }
+        let key = self.item_key(&item);
+        let handle = insert_element(
+            &self.store,
+            &self.revision,
+            &self.sender,
+            &key,
+            value,
+            self.access,
+            false,
+        );
+        items.insert(item, handle);
+    }
+
+    // returns false if there is no such item
+    pub fn update(&self, item: &K, value: V) -> bool {
+        let handle = self.items().get(item).cloned();
+        match handle {
+            Some(handle) => {
+                handle.set(value);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // returns false if there is no such item
+    pub fn remove(&self, item: &K) -> bool {
+        match self.items().remove(item) {
+            Some(handle) => remove_element(
+                &self.store,
+                &self.revision,
+                &self.sender,
+                handle.get_key(),
+                Some(handle.data_element()),
+            ),
+            None => false,
+        }
+    }
+
+    // the handle of a single item, e.g. to validate client writes to it
+    pub fn item(&self, item: &K) -> Option<DataHandle<V>> {
+        self.items().get(item).cloned()
+    }
+
+    pub fn get(&self) -> HashMap<K, V> {
+        self.items()
+            .iter()
+            .map(|(item, handle)| (item.clone(), *handle.get()))
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.items().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items().is_empty()
+    }
+
+    // None if there is no such item
+    pub fn on_change(
+        &self,
+        item: &K,
+        handler: impl Fn(V) + Send + Sync + 'static,
+    ) -> Option<Subscription> {
+        self.item(item).map(|handle| handle.on_change(handler))
+    }
+}
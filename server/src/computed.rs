@@ -0,0 +1,100 @@
+use std::collections::HashMap;
+
+use crate::{data_handle::DataHandle, event_handler::Subscription, synchronizable::Synchronizable};
+
+// the handles a computed value is derived from, a single handle or a tuple of up to four
+pub trait Dependencies: Clone + Send + Sync + 'static {
+    type Values;
+    fn keys(&self) -> Vec<String>;
+    fn values(&self) -> Self::Values;
+    fn on_change(&self, handler: impl Fn() + Clone + Send + Sync + 'static) -> Vec<Subscription>;
+}
+
+// what `Poca::computed` accepts, e.g. `&a` or `(&a, &b)`
+pub trait IntoDependencies {
+    type Dependencies: Dependencies;
+    fn into_dependencies(self) -> Self::Dependencies;
+}
+
+impl<A: Synchronizable> Dependencies for DataHandle<A> {
+    type Values = A;
+
+    fn keys(&self) -> Vec<String> {
+        vec![self.get_key().to_string()]
+    }
+
+    fn values(&self) -> A {
+        *self.get()
+    }
+
+    fn on_change(&self, handler: impl Fn() + Clone + Send + Sync + 'static) -> Vec<Subscription> {
+        vec![DataHandle::on_change(self, move |_| handler())]
+    }
+}
+
+impl<A: Synchronizable> IntoDependencies for &DataHandle<A> {
+    type Dependencies = DataHandle<A>;
+
+    fn into_dependencies(self) -> DataHandle<A> {
+        self.clone()
+    }
+}
+
+macro_rules! dependencies {
+    ($($name:ident: $index:tt),+) => {
+        impl<$($name: Synchronizable),+> Dependencies for ($(DataHandle<$name>,)+) {
+            type Values = ($($name,)+);
+
+            fn keys(&self) -> Vec<String> {
+                vec![$(self.$index.get_key().to_string()),+]
+            }
+
+            fn values(&self) -> Self::Values {
+                ($(*self.$index.get(),)+)
+            }
+
+            fn on_change(
+                &self,
+                handler: impl Fn() + Clone + Send + Sync + 'static,
+            ) -> Vec<Subscription> {
+                vec![$({
+                    let handler = handler.clone();
+                    self.$index.on_change(move |_| handler())
+                }),+]
+            }
+        }
+
+        impl<'a, $($name: Synchronizable),+> IntoDependencies for ($(&'a DataHandle<$name>,)+) {
+            type Dependencies = ($(DataHandle<$name>,)+);
+
+            fn into_dependencies(self) -> Self::Dependencies {
+                ($(self.$index.clone(),)+)
+            }
+        }
+    };
+}
+
+dependencies!(A: 0);
+dependencies!(A: 0, B: 1);
+dependencies!(A: 0, B: 1, C: 2);
+dependencies!(A: 0, B: 1, C: 2, D: 3);
+
+// keys of computed values mapped to the keys they are computed from
+pub type DependencyGraph = HashMap<String, Vec<String>>;
+
+// the chain of keys leading from one of `from` back to `key`, if there is one
+pub fn find_cycle(graph: &DependencyGraph, key: &str, from: &[String]) -> Option<Vec<String>> {
+    for each in from {
+        if each == key {
+            return Some(vec![each.clone()]);
+        }
+        if let Some(mut path) = graph
+            .get(each)
+            .and_then(|next| find_cycle(graph, key, next))
+        {
+            path.insert(0, each.clone());
+            return Some(path);
+        }
+    }
+    None
+}
@@ -1,12 +1,25 @@
 use crate::{
-    event_handler::{EventHandler, OnChangeEventHandlerStore},
+    event_handler::{
+        next_handler_id, AsyncTasks, Concurrency, EventHandler, Handler, Subscription,
+    },
     message::Message,
-    poca::DataElement,
+    patch,
+    poca::{remove_element, set_access, Access, DataElement, DataElementInner, Revision, Store},
     synchronizable::Synchronizable,
+    throttle::Throttle,
 };
-use parking_lot::RwLock;
-use std::{marker::PhantomData, ops::Deref, sync::Arc};
-use tokio::sync::broadcast;
+use parking_lot::{Mutex, RwLock};
+use std::{
+    cell::RefCell,
+    collections::{hash_map::Entry, HashMap},
+    future::Future,
+    marker::PhantomData,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Weak,
+    },
+};
+use tokio::{runtime, sync::broadcast};
 
 pub struct DataHandle<T>
 where
@@ -16,23 +29,273 @@ where
     sender: broadcast::Sender<Message>,
     data_type: PhantomData<T>,
     data_element: DataElement,
-    on_change: OnChangeEventHandlerStore<T>,
+    revision: Revision,
+    async_tasks: Arc<AsyncTasks>,
+    lifetime: Arc<Lifetime>,
+}
+
+// shared by all clones of a handle, dropped with the last one
+struct Lifetime {
+    key: String,
+    store: Store,
+    revision: Revision,
+    sender: broadcast::Sender<Message>,
+    data_element: DataElement,
+    remove_on_drop: AtomicBool,
+    // dropped with the handle, e.g. what keeps a computed value up to date
+    subscriptions: Mutex<Vec<Subscription>>,
+}
+
+impl Drop for Lifetime {
+    fn drop(&mut self) {
+        if self.remove_on_drop.load(Ordering::SeqCst) {
+            remove_element(
+                &self.store,
+                &self.revision,
+                &self.sender,
+                &self.key,
+                Some(&self.data_element),
+            );
+        }
+    }
+}
+
+// does not keep the key alive, see `DataHandle::downgrade`
+pub(crate) struct WeakDataHandle<T>
+where
+    T: Synchronizable + 'static,
+{
+    key: String,
+    sender: broadcast::Sender<Message>,
+    data_element: Weak<RwLock<DataElementInner>>,
+    revision: Revision,
+    async_tasks: Weak<AsyncTasks>,
+    lifetime: Weak<Lifetime>,
+    data_type: PhantomData<T>,
+}
+
+impl<T> Clone for WeakDataHandle<T>
+where
+    T: Synchronizable + 'static,
+{
+    fn clone(&self) -> Self {
+        Self {
+            key: self.key.clone(),
+            sender: self.sender.clone(),
+            data_element: self.data_element.clone(),
+            revision: self.revision.clone(),
+            async_tasks: self.async_tasks.clone(),
+            lifetime: self.lifetime.clone(),
+            data_type: PhantomData,
+        }
+    }
+}
+
+impl<T> WeakDataHandle<T>
+where
+    T: Synchronizable + 'static,
+{
+    // None once the last handle is dropped
+    pub(crate) fn upgrade(&self) -> Option<DataHandle<T>> {
+        Some(DataHandle {
+            key: self.key.clone(),
+            sender: self.sender.clone(),
+            data_type: PhantomData,
+            data_element: self.data_element.upgrade()?,
+            revision: self.revision.clone(),
+            async_tasks: self.async_tasks.upgrade()?,
+            lifetime: self.lifetime.upgrade()?,
+        })
+    }
+}
+
+// handles are cheap to clone, clones share the value, the handlers and the pending async tasks
+impl<T> Clone for DataHandle<T>
+where
+    T: Synchronizable + 'static,
+{
+    fn clone(&self) -> Self {
+        Self {
+            key: self.key.clone(),
+            sender: self.sender.clone(),
+            data_type: PhantomData,
+            data_element: self.data_element.clone(),
+            revision: self.revision.clone(),
+            async_tasks: self.async_tasks.clone(),
+            lifetime: self.lifetime.clone(),
+        }
+    }
+}
+
+// commits a value written from Rust and returns the update for clients,
+// None for write-only keys
+pub(crate) fn write(
+    key: &str,
+    element: &mut DataElementInner,
+    value: Box<dyn Synchronizable>,
+    revision: u64,
+) -> Option<Message> {
+    // clients never see write-only or removed keys
+    let readable = element.access.readable() && !element.removed;
+    // values that fail to serialize are reported to clients with the Set frame
+    let patch = if readable && element.throttle.is_none() {
+        patch::diff(key, element.data.as_ref(), value.as_ref())
+            .ok()
+            .flatten()
+    } else {
+        None
+    };
+    element.data = value.clone();
+    element.version += 1;
+    if !readable {
+        return None;
+    }
+    let key = key.to_string();
+    let version = element.version;
+    Some(match patch {
+        Some(patch) => Message::Patch {
+            key,
+            patch,
+            revision,
+            version,
+        },
+        None => Message::Set {
+            key,
+            data: value,
+            revision,
+            version,
+            throttle: element.throttle,
+        },
+    })
+}
+
+// true if the element skips unchanged writes and the value equals the current one
+pub(crate) fn unchanged(element: &DataElementInner, value: &dyn Synchronizable) -> bool {
+    element
+        .equality
+        .as_ref()
+        .is_some_and(|equal| equal(value, element.data.as_ref()))
+}
+
+thread_local! {
+    // elements whose handlers are running on this thread,
+    // true once one of them changed the element again
+    static DISPATCHING: RefCell<HashMap<usize, bool>> = RefCell::new(HashMap::new());
+}
+
+// how often the handlers of an element run again for their own writes
+const MAX_REDISPATCHES: usize = 32;
+
+struct Dispatching(usize);
+
+impl Drop for Dispatching {
+    fn drop(&mut self) {
+        DISPATCHING.with(|dispatching| dispatching.borrow_mut().remove(&self.0));
+    }
+}
+
+pub(crate) fn run_on_change(data_element: &DataElement) {
+    // a handler setting its own key does not recurse, the handlers run again once
+    // the current dispatch is done so all of them see the final value
+    let id = Arc::as_ptr(data_element) as usize;
+    let nested = DISPATCHING.with(|dispatching| match dispatching.borrow_mut().entry(id) {
+        Entry::Occupied(mut rerun) => {
+            rerun.insert(true);
+            true
+        }
+        Entry::Vacant(entry) => {
+            entry.insert(false);
+            false
+        }
+    });
+    if nested {
+        return;
+    }
+    let _dispatching = Dispatching(id);
+    for _ in 0..=MAX_REDISPATCHES {
+        // handlers run without the lock held so they may subscribe or unsubscribe
+        let handlers: Vec<_> = data_element.read().on_change.values().cloned().collect();
+        for handler in handlers {
+            handler.execute();
+        }
+        let rerun = DISPATCHING.with(|dispatching| dispatching.borrow_mut().insert(id, false));
+        if rerun != Some(true) {
+            return;
+        }
+    }
+    // only reached by handlers that write on every run,
+    // skip_unchanged ends the loop once the value settles
+    //TODO: uniformed logging
+    println!(
+        "Stopped running on_change handlers that changed their own key {} times",
+        MAX_REDISPATCHES
+    );
+}
+
+// reads the current value of an element from inside one of its own handlers
+fn read_value<T: Synchronizable>(data_element: &Weak<RwLock<DataElementInner>>) -> Option<Box<T>> {
+    let data_element = data_element.upgrade()?;
+    let guard = data_element.read();
+    Some(guard.data.clone_any_box().downcast().unwrap())
 }
 
 impl<T> DataHandle<T>
 where
     T: Synchronizable + 'static,
 {
-    pub fn new(key: String, sender: broadcast::Sender<Message>, data_element: DataElement) -> Self {
+    pub fn new(
+        key: String,
+        sender: broadcast::Sender<Message>,
+        data_element: DataElement,
+        revision: Revision,
+        store: Store,
+    ) -> Self {
+        let lifetime = Lifetime {
+            key: key.clone(),
+            store,
+            revision: revision.clone(),
+            sender: sender.clone(),
+            data_element: data_element.clone(),
+            remove_on_drop: AtomicBool::new(false),
+            subscriptions: Mutex::new(Vec::new()),
+        };
         Self {
             key,
             sender,
             data_type: PhantomData,
             data_element,
-            on_change: Arc::new(RwLock::new(Vec::new())),
+            revision,
+            async_tasks: Arc::new(AsyncTasks::default()),
+            lifetime: Arc::new(lifetime),
         }
     }
 
+    // removes the key once the last clone of this handle is dropped,
+    // handles captured by handlers of the key itself keep it alive
+    pub fn remove_on_drop(self) -> Self {
+        self.lifetime.remove_on_drop.store(true, Ordering::SeqCst);
+        self
+    }
+
+    // restricts what clients may do with this key, e.g. `POCA.data(..).access(Access::ReadOnly)`,
+    // the value was already sent when the key was registered, see `Poca::data_with_access`
+    pub fn access(self, access: Access) -> Self {
+        set_access(
+            &self.revision,
+            &self.sender,
+            &self.key,
+            &mut self.data_element.write(),
+            access,
+        );
+        self
+    }
+
+    // limits how often clients receive updates of this key
+    pub fn throttle(self, throttle: Throttle) -> Self {
+        self.data_element.write().throttle = Some(throttle);
+        self
+    }
+
     pub fn get_key(&self) -> &str {
         &self.key
     }
@@ -40,20 +303,46 @@ where
     pub fn set(&self, value: T) {
         {
             let mut guard = self.data_element.write();
-            guard.data = value.clone_synchronizable();
-        }
-        {
-            let handle = self.data_element.read();
-            for each in &handle.on_change {
-                let handler = each.deref();
-                handler.execute();
+            if unchanged(&guard, &value) {
+                return;
+            }
+            let revision = self.revision.fetch_add(1, Ordering::SeqCst) + 1;
+            // broadcast while still holding the lock so frames go out in revision order
+            if let Some(update) = write(&self.key, &mut guard, Box::new(value), revision) {
+                self.sender.send(update).ok();
             }
         }
-        let request = Message::Set {
-            key: self.key.to_owned(),
-            data: Box::new(value),
-        };
-        self.sender.send(request).unwrap();
+        run_on_change(&self.data_element);
+    }
+
+    pub(crate) fn data_element(&self) -> &DataElement {
+        &self.data_element
+    }
+
+    pub(crate) fn downgrade(&self) -> WeakDataHandle<T> {
+        WeakDataHandle {
+            key: self.key.clone(),
+            sender: self.sender.clone(),
+            data_element: Arc::downgrade(&self.data_element),
+            revision: self.revision.clone(),
+            async_tasks: Arc::downgrade(&self.async_tasks),
+            lifetime: Arc::downgrade(&self.lifetime),
+            data_type: PhantomData,
+        }
+    }
+
+    // the subscriptions end once the last clone of this handle is dropped
+    pub(crate) fn hold(&self, subscriptions: Vec<Subscription>) {
+        self.lifetime.subscriptions.lock().extend(subscriptions);
+    }
+
+    fn subscribe(&self, handler: Handler) -> Subscription {
+        let id = next_handler_id();
+        self.data_element.write().on_change.insert(id, handler);
+        let data_element = self.data_element.clone();
+        Subscription::new(move || {
+            data_element.write().on_change.remove(&id);
+        })
     }
 
     pub fn get(&self) -> Box<T> {
@@ -61,19 +350,89 @@ where
         guard.data.clone_any_box().downcast().unwrap()
     }
 
-    pub fn on_change(&'static self, handler: impl Fn(T) + Send + Sync + 'static) {
-        let boxed_handler = Box::new(handler);
-        let mut lock = self.on_change.write();
-        let current_index = lock.len();
-        lock.push(boxed_handler);
-        let new_handler = move || {
-            let mut guard = self.on_change.write();
-            let handler = guard.get_mut(current_index).unwrap();
-            let value = self.get();
-            (*handler)(*value);
+    pub fn version(&self) -> u64 {
+        self.data_element.read().version
+    }
+
+    // writes of a value equal to the current one are dropped, nothing is sent
+    // to clients and no handlers run, this applies to client writes too
+    pub fn skip_unchanged(self) -> Self
+    where
+        T: PartialEq,
+    {
+        let equality = |new: &dyn Synchronizable, old: &dyn Synchronizable| {
+            new.as_any().downcast_ref::<T>() == old.as_any().downcast_ref::<T>()
+        };
+        self.data_element.write().equality = Some(Box::new(equality));
+        self
+    }
+
+    // like `skip_unchanged` for types without PartialEq, compares the serialized values
+    pub fn skip_unchanged_serialized(self) -> Self {
+        let key = self.key.clone();
+        let equality = move |new: &dyn Synchronizable, old: &dyn Synchronizable| {
+            matches!(
+                (new.serialize(&key), old.serialize(&key)),
+                (Ok(new), Ok(old)) if new == old
+            )
         };
-        let dyn_handler = Box::new(new_handler) as Box<dyn Fn() + Send + Sync>;
-        let mut guard = self.data_element.write();
-        guard.on_change.push(dyn_handler);
+        self.data_element.write().equality = Some(Box::new(equality));
+        self
+    }
+
+    // rejects client writes for which the validator returns an error,
+    // the reason is sent back to the client in an Error frame
+    pub fn validate(
+        self,
+        validator: impl Fn(&T, &T) -> Result<(), String> + Send + Sync + 'static,
+    ) -> Self {
+        let validator = move |new: &dyn Synchronizable, old: &dyn Synchronizable| {
+            validator(
+                new.as_any().downcast_ref().unwrap(),
+                old.as_any().downcast_ref().unwrap(),
+            )
+        };
+        self.data_element
+            .write()
+            .validators
+            .push(Arc::new(validator));
+        self
+    }
+
+    pub fn on_change(&self, handler: impl Fn(T) + Send + Sync + 'static) -> Subscription {
+        // handlers are owned by the element, a weak reference avoids a cycle
+        let data_element = Arc::downgrade(&self.data_element);
+        self.subscribe(Arc::new(move || {
+            if let Some(value) = read_value(&data_element) {
+                handler(*value);
+            }
+        }))
+    }
+
+    // spawns the handler on the Tokio runtime this is called from,
+    // so it may await without blocking the thread that changed the value,
+    // panics if called outside a runtime
+    pub fn on_change_async<Fut>(
+        &self,
+        concurrency: Concurrency,
+        handler: impl Fn(T) -> Fut + Send + Sync + 'static,
+    ) -> Subscription
+    where
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let runtime = runtime::Handle::try_current()
+            .expect("on_change_async must be called from within a Tokio runtime");
+        let data_element = Arc::downgrade(&self.data_element);
+        let async_tasks = self.async_tasks.clone();
+        self.subscribe(Arc::new(move || {
+            if let Some(value) = read_value(&data_element) {
+                async_tasks.spawn(&runtime, concurrency, handler(*value));
+            }
+        }))
+    }
+
+    // resolves once all async handlers triggered so far have finished
+    pub async fn settled(&self) {
+        self.async_tasks.settled().await
     }
 }
@@ -0,0 +1,54 @@
+use std::fmt::{self, Display};
+
+use json_patch::PatchError;
+
+#[derive(Debug)]
+pub enum Error {
+    Serialize {
+        key: String,
+        source: serde_json::Error,
+    },
+    Deserialize {
+        key: String,
+        source: serde_json::Error,
+    },
+    Patch {
+        key: String,
+        source: PatchError,
+    },
+}
+
+impl Error {
+    pub fn key(&self) -> &str {
+        match self {
+            Error::Serialize { key, .. }
+            | Error::Deserialize { key, .. }
+            | Error::Patch { key, .. } => key,
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Serialize { key, source } => {
+                write!(f, "Failed to serialize data with key {}: {}", key, source)
+            }
+            Error::Deserialize { key, source } => {
+                write!(f, "Failed to deserialize data with key {}: {}", key, source)
+            }
+            Error::Patch { key, source } => {
+                write!(f, "Failed to patch data with key {}: {}", key, source)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Serialize { source, .. } | Error::Deserialize { source, .. } => Some(source),
+            Error::Patch { source, .. } => Some(source),
+        }
+    }
+}
@@ -1,10 +1,76 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{BTreeMap, HashMap},
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+};
 
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
+use serde_json::Value;
+use tokio::{runtime::Handle, sync::Notify, task::JoinHandle};
 
-pub type OnChangeEventHandlerStore<T> = Arc<RwLock<Vec<Box<dyn FnMut(T) + Send + Sync + 'static>>>>;
-pub type EventHandlerStore =
-    Arc<RwLock<HashMap<String, Vec<Box<dyn Fn() + Send + Sync + 'static>>>>>;
+use crate::synchronizable::Synchronizable;
+
+pub type HandlerId = u64;
+pub type Handler = Arc<dyn Fn() + Send + Sync + 'static>;
+// handlers are keyed by id so they can be removed again, ids only grow so they run in registration order
+pub type Handlers<H = Handler> = BTreeMap<HandlerId, H>;
+
+// deserializes the payload of an Emit frame and returns the call to run,
+// so nothing runs unless the payload fits every handler of the event
+pub type EventCall = Box<dyn FnOnce() + Send>;
+pub type PayloadHandler =
+    Arc<dyn Fn(&Value) -> Result<EventCall, serde_json::Error> + Send + Sync + 'static>;
+pub type EventHandlerStore = Arc<RwLock<HashMap<String, Handlers<PayloadHandler>>>>;
+
+// errors are sent back to the caller as text
+pub type RpcResult = Result<Box<dyn Synchronizable>, String>;
+pub type RpcCall = Pin<Box<dyn Future<Output = RpcResult> + Send>>;
+// deserializes the request of a Call frame and starts the call
+pub type RpcHandler =
+    Arc<dyn Fn(&Value) -> Result<RpcCall, serde_json::Error> + Send + Sync + 'static>;
+pub type RpcHandlerStore = Arc<RwLock<HashMap<String, RpcHandler>>>;
+
+static NEXT_HANDLER_ID: AtomicU64 = AtomicU64::new(0);
+
+pub fn next_handler_id() -> HandlerId {
+    NEXT_HANDLER_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+// removes its handler when dropped, call `detach` to keep the handler for the lifetime of the server
+#[must_use = "the handler is removed as soon as the Subscription is dropped"]
+pub struct Subscription {
+    remove: Option<Box<dyn FnOnce() + Send + Sync>>,
+}
+
+impl Subscription {
+    pub(crate) fn new(remove: impl FnOnce() + Send + Sync + 'static) -> Self {
+        Self {
+            remove: Some(Box::new(remove)),
+        }
+    }
+
+    pub fn unsubscribe(mut self) {
+        if let Some(remove) = self.remove.take() {
+            remove();
+        }
+    }
+
+    pub fn detach(mut self) {
+        self.remove = None;
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        if let Some(remove) = self.remove.take() {
+            remove();
+        }
+    }
+}
 
 pub trait EventHandler: Send + Sync + 'static {
     fn execute(&self);
@@ -27,3 +93,73 @@ where
         Box::new(self)
     }
 }
+
+// how async on_change handlers of one key are scheduled relative to each other
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Concurrency {
+    // one at a time, in the order the changes happened
+    Serial,
+    Concurrent,
+}
+
+// async handlers spawned for one key
+#[derive(Default)]
+pub struct AsyncTasks {
+    pending: AtomicUsize,
+    settled: Notify,
+    // the next serial handler waits for this one
+    last_serial: Mutex<Option<JoinHandle<()>>>,
+}
+
+// decrements the pending count even if the handler panics
+struct PendingGuard(Arc<AsyncTasks>);
+
+impl Drop for PendingGuard {
+    fn drop(&mut self) {
+        if self.0.pending.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.0.settled.notify_waiters();
+        }
+    }
+}
+
+impl AsyncTasks {
+    pub fn spawn(
+        self: &Arc<Self>,
+        runtime: &Handle,
+        concurrency: Concurrency,
+        handler: impl Future<Output = ()> + Send + 'static,
+    ) {
+        self.pending.fetch_add(1, Ordering::SeqCst);
+        let guard = PendingGuard(self.clone());
+        let task = async move {
+            let _guard = guard;
+            handler.await;
+        };
+        match concurrency {
+            Concurrency::Concurrent => {
+                runtime.spawn(task);
+            }
+            Concurrency::Serial => {
+                let mut last_serial = self.last_serial.lock();
+                let previous = last_serial.take();
+                *last_serial = Some(runtime.spawn(async move {
+                    if let Some(previous) = previous {
+                        previous.await.ok();
+                    }
+                    task.await;
+                }));
+            }
+        }
+    }
+
+    // resolves once every handler spawned so far has finished
+    pub async fn settled(&self) {
+        loop {
+            let notified = self.settled.notified();
+            if self.pending.load(Ordering::SeqCst) == 0 {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
@@ -1,14 +1,29 @@
 mod app_routes;
+mod codec;
+mod collection;
+mod computed;
 mod data_handle;
+mod error;
 mod event_handler;
 mod message;
+mod patch;
 mod poca;
+mod scope;
 mod synchronizable;
+mod throttle;
+mod transaction;
 mod ws_handler;
 
 pub use app_routes::AppRoutes as _AppRoutes;
+pub use collection::CollectionHandle;
+pub use computed::{Dependencies, IntoDependencies};
 pub use data_handle::DataHandle;
-pub use poca::{Poca, WindowOptions};
+pub use error::Error;
+pub use event_handler::{Concurrency, Subscription};
+pub use poca::{Access, ClientId, Poca, WindowOptions};
+pub use scope::Scope;
+pub use throttle::Throttle;
+pub use transaction::Transaction;
 
 // macro-related functions
 // should not be documented
@@ -1,18 +1,71 @@
+use std::collections::HashMap;
+
+use json_patch::Patch;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use serde_repr::*;
 
-use crate::synchronizable::Synchronizable;
+use crate::{error::Error, synchronizable::Synchronizable, throttle::Throttle};
+
+pub const PROTOCOL_VERSION: u32 = 1;
 
 #[derive(Debug, Clone)]
 pub enum Message {
     Set {
         key: String,
         data: Box<dyn Synchronizable>,
+        revision: u64,
+        version: u64,
+        // the element's throttle when the frame was built, applied by each client's dealer
+        throttle: Option<Throttle>,
     },
+    // RFC 6902 patch against the value at version - 1
+    Patch {
+        key: String,
+        patch: Patch,
+        revision: u64,
+        version: u64,
+    },
+    // only sent through the requesting client's own channel
     Get {
-        //TODO: use client-id to only reply to the client that requested the data
+        id: Option<u64>,
         key: String,
         data: Box<dyn Synchronizable>,
+        version: u64,
+    },
+    // the values of all readable keys, or of one scope if key is set
+    Snapshot {
+        id: Option<u64>,
+        key: Option<String>,
+        revision: u64,
+        entries: HashMap<String, SnapshotEntry>,
+    },
+    // the key no longer exists, clients drop what they hold for it
+    Removed {
+        key: String,
+        revision: u64,
+    },
+    // updates committed together by a transaction, all with the same revision
+    Batch {
+        revision: u64,
+        updates: Vec<Message>,
+    },
+    // a one-shot event from the server, not kept in the store
+    Emit {
+        key: String,
+        data: Box<dyn Synchronizable>,
+    },
+    // the result of a Call, only sent to the calling client
+    Reply {
+        id: Option<u64>,
+        key: String,
+        data: Box<dyn Synchronizable>,
+    },
+    // only sent through the requesting client's own channel
+    Error {
+        id: Option<u64>,
+        key: Option<String>,
+        error: WSError,
     },
 }
 
@@ -23,11 +76,126 @@ pub enum WSMessageType {
     Emit = 2,
     Get = 3,
     Error = 4,
+    Hello = 5,
+    Welcome = 6,
+    Snapshot = 7,
+    Patch = 8,
+    Call = 9,
+    Reply = 10,
+    Batch = 11,
+    Removed = 12,
+    Subscribe = 13,
+    Unsubscribe = 14,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    MalformedMessage,
+    MissingKey,
+    MissingData,
+    MissingVersion,
+    UnknownKey,
+    UnknownEvent,
+    InvalidPayload,
+    UnknownRpc,
+    RpcFailed,
+    UnsupportedMessageType,
+    HandshakeRequired,
+    VersionMismatch,
+    UnsupportedEncoding,
+    VersionConflict,
+    InvalidPatch,
+    InvalidData,
+    SerializationFailed,
+    ValidationFailed,
+    Forbidden,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WSError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl WSError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        WSError {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<Error> for WSError {
+    fn from(error: Error) -> Self {
+        let code = match error {
+            Error::Serialize { .. } => ErrorCode::SerializationFailed,
+            Error::Deserialize { .. } => ErrorCode::InvalidData,
+            Error::Patch { .. } => ErrorCode::InvalidPatch,
+        };
+        WSError::new(code, error.to_string())
+    }
+}
+
+// fields that are not set are left out of the frame
 #[derive(Serialize, Deserialize, Debug)]
 pub struct WSMessage {
     pub message_type: WSMessageType,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub key: Option<String>,
-    pub data: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub revision: Option<u64>,
+    // per-key version of the carried data
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<u64>,
+    // Set from a client is rejected unless the element is still at this version,
+    // required on Patch
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expected_version: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<WSError>,
+}
+
+impl WSMessage {
+    pub fn new(message_type: WSMessageType) -> Self {
+        WSMessage {
+            message_type,
+            id: None,
+            key: None,
+            data: None,
+            revision: None,
+            version: None,
+            expected_version: None,
+            error: None,
+        }
+    }
+}
+
+// sent by the client as the data of its first message
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Hello {
+    pub version: u32,
+    pub encodings: Vec<String>,
+}
+
+// the server's reply to a successful Hello
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Welcome {
+    pub version: u32,
+    pub session_id: u64,
+    pub encoding: String,
+    pub keys: Vec<String>,
+    pub events: Vec<String>,
+    pub rpcs: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SnapshotEntry {
+    pub data: Value,
+    pub version: u64,
 }
@@ -0,0 +1,32 @@
+use json_patch::Patch;
+
+use crate::{error::Error, synchronizable::Synchronizable};
+
+// Some(patch) only when sending it is cheaper than sending the whole new value
+pub fn diff(
+    key: &str,
+    previous: &dyn Synchronizable,
+    next: &dyn Synchronizable,
+) -> Result<Option<Patch>, Error> {
+    let next = next.serialize(key)?;
+    let patch = json_patch::diff(&previous.serialize(key)?, &next);
+    let patch_size = serde_json::to_vec(&patch).unwrap().len();
+    if patch_size < serde_json::to_vec(&next).unwrap().len() {
+        Ok(Some(patch))
+    } else {
+        Ok(None)
+    }
+}
+
+pub fn apply(
+    key: &str,
+    current: &dyn Synchronizable,
+    patch: &Patch,
+) -> Result<Box<dyn Synchronizable>, Error> {
+    let mut value = current.serialize(key)?;
+    json_patch::patch(&mut value, patch).map_err(|source| Error::Patch {
+        key: key.to_string(),
+        source,
+    })?;
+    current.deserialize(key, value)
+}
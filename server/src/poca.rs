@@ -1,28 +1,86 @@
 use std::{
-    collections::HashMap,
-    fmt::Debug,
+    collections::{HashMap, HashSet},
+    fmt::{Debug, Display},
+    future::{self, Future},
+    hash::Hash,
     net::{SocketAddr, ToSocketAddrs},
-    sync::Arc,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
 };
 
 use parking_lot::{Mutex, RwLock};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
 use tokio::{
-    sync::{broadcast, oneshot},
+    sync::{broadcast, mpsc, oneshot},
     task::JoinHandle,
 };
 use warp::{path::FullPath, Filter};
 use web_view::Handle;
 
 use crate::{
-    app_routes::AppRoutes, data_handle::DataHandle, event_handler::EventHandlerStore,
-    message::Message, synchronizable::Synchronizable, ws_handler::websocket_handler,
+    app_routes::AppRoutes,
+    collection::{CollectionHandle, Collections},
+    computed::{find_cycle, Dependencies, DependencyGraph, IntoDependencies},
+    data_handle::{self, DataHandle},
+    event_handler::{
+        next_handler_id, EventCall, EventHandlerStore, Handlers, PayloadHandler, RpcCall,
+        RpcHandler, RpcHandlerStore, Subscription,
+    },
+    message::Message,
+    scope::Scope,
+    synchronizable::Synchronizable,
+    throttle::Throttle,
+    transaction::Transaction,
+    ws_handler::{websocket_handler, Connection},
 };
 
+// default capacity of the broadcast channel, clients lagging further behind get a new snapshot
 const CHANNEL_SIZE: usize = 32;
 
+// what connected clients may do with a key, Rust code can always read and write
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Access {
+    ReadWrite,
+    ReadOnly,
+    WriteOnly,
+}
+
+impl Access {
+    pub fn readable(&self) -> bool {
+        *self != Access::WriteOnly
+    }
+
+    pub fn writable(&self) -> bool {
+        *self != Access::ReadOnly
+    }
+}
+
+// receives the proposed value and the current one
+pub type Validator =
+    Arc<dyn Fn(&dyn Synchronizable, &dyn Synchronizable) -> Result<(), String> + Send + Sync>;
+// true if writing the proposed value over the current one would change nothing
+pub type Equality = Box<dyn Fn(&dyn Synchronizable, &dyn Synchronizable) -> bool + Send + Sync>;
+
 pub struct DataElementInner {
     pub data: Box<dyn Synchronizable>,
-    pub on_change: Vec<Box<dyn Fn() + Send + Sync>>,
+    // incremented on every write
+    pub version: u64,
+    pub access: Access,
+    pub on_change: Handlers,
+    // run on writes coming from clients before they are committed
+    pub validators: Vec<Validator>,
+    // throttled keys are always sent as Set so only the latest value matters
+    pub throttle: Option<Throttle>,
+    // set once the key is removed from the store, writes through
+    // remaining handles are no longer sent to clients
+    pub removed: bool,
+    // the keys a computed value is derived from, see `Poca::computed`
+    pub dependencies: Vec<String>,
+    // writes of an equal value are skipped entirely when set
+    pub equality: Option<Equality>,
 }
 
 impl Debug for DataElementInner {
@@ -33,19 +91,161 @@ impl Debug for DataElementInner {
 
 pub type DataElement = Arc<RwLock<DataElementInner>>;
 pub type Store = Arc<Mutex<HashMap<String, DataElement>>>;
+// bumped on every write to any element, used to order snapshots against Set frames
+pub type Revision = Arc<AtomicU64>;
 
 pub type BroadcastSender = broadcast::Sender<Message>;
 pub type BroadcastReceiver = broadcast::Receiver<Message>;
 
+pub type ClientId = u64;
+pub type ClientSender = mpsc::UnboundedSender<Message>;
+pub type ClientStore = Arc<Mutex<HashMap<ClientId, ClientSender>>>;
+
+// panics if the key exists and `replace` is not set
+pub(crate) fn insert_element<T: Synchronizable>(
+    store: &Store,
+    revision: &Revision,
+    sender: &BroadcastSender,
+    key: &str,
+    data: T,
+    access: Access,
+    replace: bool,
+) -> DataHandle<T> {
+    let mut guard = store.lock();
+    // checked first so the existing element survives the panic
+    if !replace && guard.contains_key(key) {
+        panic!("Key {} already exists", key);
+    }
+    let previous = guard.remove(key);
+    if let Some(previous) = &previous {
+        mark_removed(revision, sender, key, previous);
+    }
+    let data = Arc::new(RwLock::new(DataElementInner {
+        data: data.clone_synchronizable(),
+        version: 0,
+        access,
+        on_change: Handlers::new(),
+        validators: Vec::new(),
+        throttle: None,
+        removed: false,
+        dependencies: Vec::new(),
+        equality: None,
+    }));
+    // connected clients learn about keys registered at runtime, after any Removed
+    // of the element it replaces
+    announce(revision, sender, key, &data.read());
+    guard.insert(key.to_string(), data.clone());
+    // dropping the last reference to the previous element may drop handles captured
+    // by its handlers, and a `remove_on_drop` handle locks the store again
+    drop(guard);
+    drop(previous);
+    DataHandle::new(
+        key.to_string(),
+        sender.clone(),
+        data,
+        revision.clone(),
+        store.clone(),
+    )
+}
+
+// removes the element with this key, or only `element` if given and it is still in the store
+pub(crate) fn remove_element(
+    store: &Store,
+    revision: &Revision,
+    sender: &BroadcastSender,
+    key: &str,
+    element: Option<&DataElement>,
+) -> bool {
+    let mut store_lock = store.lock();
+    match (store_lock.get(key), element) {
+        (Some(current), Some(element)) if !Arc::ptr_eq(current, element) => return false,
+        (None, _) => return false,
+        _ => {}
+    }
+    let removed = store_lock.remove(key).unwrap();
+    mark_removed(revision, sender, key, &removed);
+    // see insert_element, the store must not be locked once the element may go away
+    drop(store_lock);
+    drop(removed);
+    true
+}
+
+// sends the whole value to clients if they may read it
+fn announce(revision: &Revision, sender: &BroadcastSender, key: &str, element: &DataElementInner) {
+    if element.access.readable() && !element.removed {
+        let revision = revision.fetch_add(1, Ordering::SeqCst) + 1;
+        sender
+            .send(Message::Set {
+                key: key.to_string(),
+                data: element.data.clone(),
+                revision,
+                version: element.version,
+                throttle: element.throttle,
+            })
+            .ok();
+    }
+}
+
+// clients are sent the key once they may read it and told to drop it once they may not
+pub(crate) fn set_access(
+    revision: &Revision,
+    sender: &BroadcastSender,
+    key: &str,
+    element: &mut DataElementInner,
+    access: Access,
+) {
+    let was_readable = element.access.readable();
+    element.access = access;
+    match (was_readable, access.readable()) {
+        (false, true) => announce(revision, sender, key, element),
+        (true, false) if !element.removed => {
+            let revision = revision.fetch_add(1, Ordering::SeqCst) + 1;
+            sender
+                .send(Message::Removed {
+                    key: key.to_string(),
+                    revision,
+                })
+                .ok();
+        }
+        _ => {}
+    }
+}
+
+// expects the element to be taken out of the store already, with the store lock held
+fn mark_removed(revision: &Revision, sender: &BroadcastSender, key: &str, element: &DataElement) {
+    let mut guard = element.write();
+    guard.removed = true;
+    let revision = revision.fetch_add(1, Ordering::SeqCst) + 1;
+    if guard.access.readable() {
+        sender
+            .send(Message::Removed {
+                key: key.to_string(),
+                revision,
+            })
+            .ok();
+    }
+}
+
+// cheap to clone, every clone refers to the same server
+#[derive(Clone)]
 pub struct Poca {
+    inner: Arc<PocaInner>,
+}
+
+struct PocaInner {
     state: Mutex<ServerState>,
     address: SocketAddr,
     shutdown: Mutex<Option<oneshot::Sender<()>>>,
     store: Store,
+    revision: Revision,
     event_handler_store: EventHandlerStore,
+    rpc_handler_store: RpcHandlerStore,
+    collections: Collections,
     broadcast: (BroadcastSender, BroadcastReceiver),
+    clients: ClientStore,
+    next_client_id: Arc<AtomicU64>,
     server: Mutex<Option<JoinHandle<()>>>,
-    app_routes: AppRoutes<'static>,
+    app_routes: Arc<AppRoutes<'static>>,
     window_options: WindowOptions,
     //@TODO: support multiple windows
     window_handler: Mutex<Option<Handle<()>>>,
@@ -90,68 +290,325 @@ impl Poca {
         window_options: impl Into<Option<WindowOptions>>,
     ) -> Poca {
         let channel = broadcast::channel(CHANNEL_SIZE);
-        Poca {
+        let inner = PocaInner {
             state: Mutex::new(ServerState::Down),
             address: address.to_socket_addrs().unwrap().next().unwrap(),
             shutdown: Mutex::new(None),
             store: Arc::new(Mutex::new(HashMap::new())),
+            revision: Arc::new(AtomicU64::new(0)),
             event_handler_store: Arc::new(RwLock::new(HashMap::new())),
+            rpc_handler_store: Arc::new(RwLock::new(HashMap::new())),
+            collections: Arc::new(Mutex::new(HashSet::new())),
             broadcast: channel,
+            clients: Arc::new(Mutex::new(HashMap::new())),
+            next_client_id: Arc::new(AtomicU64::new(0)),
             server: Mutex::new(None),
-            app_routes,
+            app_routes: Arc::new(app_routes),
             window_options: window_options.into().unwrap_or(WindowOptions::default()),
             window_handler: Mutex::new(None),
+        };
+        Poca {
+            inner: Arc::new(inner),
         }
     }
 
-    pub fn data<T: Synchronizable>(&'static self, key: &str, data: T) -> DataHandle<T> {
-        let mut guard = self.store.lock();
-        if guard.contains_key(key) {
-            panic!("Key {} already exists", key);
+    // sets the capacity of the broadcast channel, call it right after `new`,
+    // e.g. `Poca::new(..).with_channel_size(1024)`
+    pub fn with_channel_size(mut self, channel_size: usize) -> Self {
+        let inner = Arc::get_mut(&mut self.inner)
+            .expect("with_channel_size must be called before the Poca is cloned");
+        assert!(
+            inner.store.lock().is_empty(),
+            "with_channel_size must be called before any data is registered"
+        );
+        inner.broadcast = broadcast::channel(channel_size);
+        self
+    }
+
+    pub fn data<T: Synchronizable>(&self, key: &str, data: T) -> DataHandle<T> {
+        self.data_with_access(key, data, Access::ReadWrite)
+    }
+
+    // registers the key with its access already in place, so the value of a
+    // write-only key is never sent to connected clients
+    pub fn data_with_access<T: Synchronizable>(
+        &self,
+        key: &str,
+        data: T,
+        access: Access,
+    ) -> DataHandle<T> {
+        self.insert_data(key, data, access, false)
+    }
+
+    // replaces the element with this key if there is one, clients are told to drop
+    // the old value and handles to it keep working without reaching clients
+    pub fn data_or_replace<T: Synchronizable>(&self, key: &str, data: T) -> DataHandle<T> {
+        self.insert_data(key, data, Access::ReadWrite, true)
+    }
+
+    // a read-only key recomputed whenever one of its dependencies changes, as long
+    // as a handle to it is alive, e.g. `poca.computed("sum", (&a, &b), |(a, b)| a + b)`
+    pub fn computed<D, T>(
+        &self,
+        key: &str,
+        dependencies: D,
+        compute: impl Fn(<D::Dependencies as Dependencies>::Values) -> T + Send + Sync + 'static,
+    ) -> DataHandle<T>
+    where
+        D: IntoDependencies,
+        T: Synchronizable,
+    {
+        let dependencies = dependencies.into_dependencies();
+        let keys = dependencies.keys();
+        if let Some(cycle) = find_cycle(&self.dependency_graph(), key, &keys) {
+            panic!(
+                "Computed key {} would depend on itself through {}",
+                key,
+                cycle.join(" -> ")
+            );
         }
-        let data = Arc::new(RwLock::new(DataElementInner {
-            data: data.clone_synchronizable(),
-            on_change: Vec::new(),
-        }));
-        guard.insert(key.to_string(), data.clone());
-        let sender = self.broadcast.0.clone();
-        DataHandle::new(key.to_string(), sender, data)
+        let handle = self
+            .data_with_access(key, compute(dependencies.values()), Access::ReadOnly)
+            // unchanged results are not written, so nothing is sent and no handlers run
+            .skip_unchanged_serialized();
+        // kept with the element, so the edges go away when the key is removed
+        handle.data_element().write().dependencies = keys;
+        let computed = handle.downgrade();
+        let compute = Arc::new(compute);
+        let subscriptions = dependencies.clone().on_change(move || {
+            // only recomputed while a handle is alive and the key was not removed
+            let computed = match computed.upgrade() {
+                Some(computed) if !computed.data_element().read().removed => computed,
+                _ => return,
+            };
+            computed.set(compute(dependencies.values()));
+        });
+        handle.hold(subscriptions);
+        handle
     }
 
-    pub fn event(&self, key: &str, handler: impl Fn() + Send + Sync + 'static) {
-        let mut lock = self.event_handler_store.write();
-        match lock.get_mut(key) {
-            Some(list) => {
-                list.push(Box::new(handler));
-            }
-            None => {
-                lock.insert(key.to_string(), vec![Box::new(handler)]);
+    fn dependency_graph(&self) -> DependencyGraph {
+        self.inner
+            .store
+            .lock()
+            .iter()
+            .filter_map(|(key, element)| {
+                let dependencies = element.read().dependencies.clone();
+                (!dependencies.is_empty()).then(|| (key.clone(), dependencies))
+            })
+            .collect()
+    }
+
+    // a map of items synchronized one item at a time, see CollectionHandle,
+    // panics if a handle to a collection with this key is still alive
+    pub fn collection<K, V>(&self, key: &str) -> CollectionHandle<K, V>
+    where
+        K: Display + Eq + Hash + Clone + Send + Sync + 'static,
+        V: Synchronizable,
+    {
+        CollectionHandle::new(
+            key.to_string(),
+            self.inner.store.clone(),
+            self.inner.revision.clone(),
+            self.inner.broadcast.0.clone(),
+            self.inner.collections.clone(),
+        )
+    }
+
+    // prefixes the keys of everything registered through it, e.g. `poca.scope("settings")`
+    pub fn scope(&self, name: &str) -> Scope {
+        Scope::new(self.clone(), name.to_string())
+    }
+
+    // returns false if there is no element with this key
+    pub fn remove_data(&self, key: &str) -> bool {
+        remove_element(
+            &self.inner.store,
+            &self.inner.revision,
+            &self.inner.broadcast.0,
+            key,
+            None,
+        )
+    }
+
+    fn insert_data<T: Synchronizable>(
+        &self,
+        key: &str,
+        data: T,
+        access: Access,
+        replace: bool,
+    ) -> DataHandle<T> {
+        insert_element(
+            &self.inner.store,
+            &self.inner.revision,
+            &self.inner.broadcast.0,
+            key,
+            data,
+            access,
+            replace,
+        )
+    }
+
+    // the payload sent with the Emit frame is deserialized into P,
+    // use `()` for events without a payload
+    pub fn event<P>(&self, key: &str, handler: impl Fn(P) + Send + Sync + 'static) -> Subscription
+    where
+        P: DeserializeOwned + Send + 'static,
+    {
+        let id = next_handler_id();
+        let handler = Arc::new(handler);
+        let payload_handler: PayloadHandler = Arc::new(move |payload: &Value| {
+            let payload: P = serde_json::from_value(payload.clone())?;
+            let handler = handler.clone();
+            Ok(Box::new(move || handler(payload)) as EventCall)
+        });
+        self.inner
+            .event_handler_store
+            .write()
+            .entry(key.to_string())
+            .or_default()
+            .insert(id, payload_handler);
+        let store = self.inner.event_handler_store.clone();
+        let key = key.to_string();
+        Subscription::new(move || {
+            let mut lock = store.write();
+            if let Some(handlers) = lock.get_mut(&key) {
+                handlers.remove(&id);
+                // events without handlers are no longer advertised to clients
+                if handlers.is_empty() {
+                    lock.remove(&key);
+                }
             }
+        })
+    }
+
+    // answers Call frames with the given key, the reply only goes to the calling client
+    pub fn rpc<Req, Resp, E>(
+        &self,
+        key: &str,
+        handler: impl Fn(Req) -> Result<Resp, E> + Send + Sync + 'static,
+    ) -> Subscription
+    where
+        Req: DeserializeOwned + Send + 'static,
+        Resp: Synchronizable,
+        E: Display,
+    {
+        self.register_rpc(
+            key,
+            Arc::new(move |request: &Value| {
+                let request: Req = serde_json::from_value(request.clone())?;
+                let result = handler(request)
+                    .map(|response| Box::new(response) as Box<dyn Synchronizable>)
+                    .map_err(|error| error.to_string());
+                Ok(Box::pin(future::ready(result)) as RpcCall)
+            }),
+        )
+    }
+
+    pub fn rpc_async<Req, Resp, E, Fut>(
+        &self,
+        key: &str,
+        handler: impl Fn(Req) -> Fut + Send + Sync + 'static,
+    ) -> Subscription
+    where
+        Req: DeserializeOwned + Send + 'static,
+        Resp: Synchronizable,
+        E: Display,
+        Fut: Future<Output = Result<Resp, E>> + Send + 'static,
+    {
+        self.register_rpc(
+            key,
+            Arc::new(move |request: &Value| {
+                let request: Req = serde_json::from_value(request.clone())?;
+                let call = handler(request);
+                Ok(Box::pin(async move {
+                    call.await
+                        .map(|response| Box::new(response) as Box<dyn Synchronizable>)
+                        .map_err(|error| error.to_string())
+                }) as RpcCall)
+            }),
+        )
+    }
+
+    fn register_rpc(&self, key: &str, handler: RpcHandler) -> Subscription {
+        let mut lock = self.inner.rpc_handler_store.write();
+        if lock.contains_key(key) {
+            panic!("Rpc {} already exists", key);
+        }
+        lock.insert(key.to_string(), handler);
+        let store = self.inner.rpc_handler_store.clone();
+        let key = key.to_string();
+        Subscription::new(move || {
+            store.write().remove(&key);
+        })
+    }
+
+    // sends an event to every connected client
+    pub fn emit<P: Synchronizable>(&self, key: &str, payload: P) {
+        let message = Message::Emit {
+            key: key.to_string(),
+            data: Box::new(payload),
+        };
+        self.inner.broadcast.0.send(message).ok();
+    }
+
+    // returns false if the client is no longer connected
+    pub fn emit_to<P: Synchronizable>(&self, client_id: ClientId, key: &str, payload: P) -> bool {
+        let message = Message::Emit {
+            key: key.to_string(),
+            data: Box::new(payload),
+        };
+        match self.inner.clients.lock().get(&client_id) {
+            Some(sender) => sender.send(message).is_ok(),
+            None => false,
+        }
+    }
+
+    // ids of the clients currently connected
+    pub fn clients(&self) -> Vec<ClientId> {
+        self.inner.clients.lock().keys().copied().collect()
+    }
+
+    // e.g. `poca.transaction(|tx| { tx.set(&a, 1); tx.set(&b, 2); })`, clients receive
+    // all writes in one Batch frame and on_change handlers run after the commit
+    pub fn transaction(&self, build: impl FnOnce(&mut Transaction)) {
+        let mut transaction = Transaction::default();
+        build(&mut transaction);
+        let elements = transaction.commit(
+            &self.inner.store,
+            &self.inner.revision,
+            &self.inner.broadcast.0,
+        );
+        for element in &elements {
+            data_handle::run_on_change(element);
         }
     }
 
     pub fn get_state(&self) -> ServerState {
-        *self.state.lock()
+        *self.inner.state.lock()
     }
 
     //@TODO: choose if the program should end when window is closed
     pub fn show_window(&self) {
-        if self.window_handler.lock().is_none() {
+        if self.inner.window_handler.lock().is_none() {
             let window = web_view::builder()
-                .title(self.window_options.title.as_str())
-                .content(web_view::Content::Url(format!("http://{}/", self.address)))
+                .title(self.inner.window_options.title.as_str())
+                .content(web_view::Content::Url(format!(
+                    "http://{}/",
+                    self.inner.address
+                )))
                 .size(
-                    self.window_options.size.0 as i32,
-                    self.window_options.size.1 as i32,
+                    self.inner.window_options.size.0 as i32,
+                    self.inner.window_options.size.1 as i32,
                 )
-                .resizable(self.window_options.resizable)
+                .resizable(self.inner.window_options.resizable)
                 .debug(false)
                 .user_data(())
                 .invoke_handler(|_webview, _argument| Ok(()))
                 .build()
                 .expect("Failed to build Webview window");
             let handle = window.handle();
-            *(self.window_handler.lock()) = Some(handle);
+            *(self.inner.window_handler.lock()) = Some(handle);
             window.run().ok();
         } else {
             panic!("Window already shown")
@@ -159,29 +616,39 @@ impl Poca {
     }
 
     pub fn kill_window(&self) {
-        if let Some(handle) = self.window_handler.lock().take() {
-            handle.dispatch(|webview| Ok(webview.exit())).ok();
-        }
+        self.inner.kill_window();
     }
 
-    pub async fn start(&'static self) {
+    pub async fn start(&self) {
         let (shutdown_sender, shutdown_receiver) = oneshot::channel();
 
+        // the routes only hold the shared parts they need, so dropping the last
+        // Poca still shuts the server down
+        let next_client_id = self.inner.next_client_id.clone();
+        let store = self.inner.store.clone();
+        let revision = self.inner.revision.clone();
+        let event_handler_store = self.inner.event_handler_store.clone();
+        let rpc_handler_store = self.inner.rpc_handler_store.clone();
+        let broadcast_sender = self.inner.broadcast.0.clone();
+        let clients = self.inner.clients.clone();
+        let app_routes = self.inner.app_routes.clone();
+
         let routes = warp::get().and(
             warp::any()
-                .and(warp::ws().map(|websocket: warp::ws::Ws| {
-                    let store = self.store.clone();
-                    let event_handler_store = self.event_handler_store.clone();
-                    let broadcast_receiver = self.broadcast.0.subscribe();
-                    let broadcast_sender = self.broadcast.0.clone();
-                    websocket.on_upgrade(|websocket| {
-                        websocket_handler(
-                            websocket,
-                            store,
-                            event_handler_store,
-                            broadcast_receiver,
-                            broadcast_sender,
-                        )
+                .and(warp::ws().map(move |websocket: warp::ws::Ws| {
+                    let connection = Connection {
+                        client_id: next_client_id.fetch_add(1, Ordering::Relaxed),
+                        store: store.clone(),
+                        revision: revision.clone(),
+                        event_handler_store: event_handler_store.clone(),
+                        rpc_handler_store: rpc_handler_store.clone(),
+                        broadcast_sender: broadcast_sender.clone(),
+                        clients: clients.clone(),
+                        scopes: Default::default(),
+                    };
+                    let broadcast_receiver = broadcast_sender.subscribe();
+                    websocket.on_upgrade(move |websocket| {
+                        websocket_handler(websocket, connection, broadcast_receiver)
                     })
                 }))
                 .or(warp::any()
@@ -218,25 +685,42 @@ impl Poca {
                             },
                             None => "text/html",
                         };
-                        let content = self.app_routes.get_route(&path, true).unwrap_or(&[]);
+                        let content = app_routes.get_route(&path, true).unwrap_or(&[]);
                         warp::reply::with_header(content, "content-type", content_type)
                     })),
         );
 
-        let address = self.address;
+        let address = self.inner.address;
 
-        *(self.server.lock()) = Some(tokio::spawn(async move {
+        *(self.inner.server.lock()) = Some(tokio::spawn(async move {
             let (_, server) = warp::serve(routes).bind_with_graceful_shutdown(address, async {
                 shutdown_receiver.await.ok();
             });
             server.await;
         }));
 
-        *(self.shutdown.lock()) = Some(shutdown_sender);
-        *(self.state.lock()) = ServerState::Up;
+        *(self.inner.shutdown.lock()) = Some(shutdown_sender);
+        *(self.inner.state.lock()) = ServerState::Up;
     }
 
     pub fn stop(&self) {
+        self.inner.stop();
+    }
+}
+
+impl PocaInner {
+    fn kill_window(&self) {
+        if let Some(handle) = self.window_handler.lock().take() {
+            handle
+                .dispatch(|webview| {
+                    webview.exit();
+                    Ok(())
+                })
+                .ok();
+        }
+    }
+
+    fn stop(&self) {
         if *(self.state.lock()) == ServerState::Up {
             self.kill_window();
             if let Some(sender) = self.shutdown.lock().take() {
@@ -247,7 +731,8 @@ impl Poca {
     }
 }
 
-impl Drop for Poca {
+// runs once the last clone of Poca is gone
+impl Drop for PocaInner {
     fn drop(&mut self) {
         self.stop();
     }
@@ -0,0 +1,120 @@
+use std::{fmt::Display, future::Future, hash::Hash};
+
+use serde::de::DeserializeOwned;
+
+use crate::{
+    collection::CollectionHandle,
+    computed::{Dependencies, IntoDependencies},
+    data_handle::DataHandle,
+    event_handler::Subscription,
+    poca::{Access, Poca},
+    synchronizable::Synchronizable,
+};
+
+// a view of Poca that prefixes every key with "{name}.", e.g. `settings.theme`,
+// clients can snapshot or subscribe to a whole scope by its name
+#[derive(Clone)]
+pub struct Scope {
+    poca: Poca,
+    prefix: String,
+}
+
+impl Scope {
+    pub(crate) fn new(poca: Poca, prefix: String) -> Self {
+        Self { poca, prefix }
+    }
+
+    pub fn get_prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    // the full key as seen by clients
+    pub fn key(&self, key: &str) -> String {
+        format!("{}.{}", self.prefix, key)
+    }
+
+    pub fn scope(&self, name: &str) -> Scope {
+        Scope::new(self.poca.clone(), self.key(name))
+    }
+
+    pub fn data<T: Synchronizable>(&self, key: &str, data: T) -> DataHandle<T> {
+        self.poca.data(&self.key(key), data)
+    }
+
+    pub fn data_with_access<T: Synchronizable>(
+        &self,
+        key: &str,
+        data: T,
+        access: Access,
+    ) -> DataHandle<T> {
+        self.poca.data_with_access(&self.key(key), data, access)
+    }
+
+    pub fn data_or_replace<T: Synchronizable>(&self, key: &str, data: T) -> DataHandle<T> {
+        self.poca.data_or_replace(&self.key(key), data)
+    }
+
+    pub fn remove_data(&self, key: &str) -> bool {
+        self.poca.remove_data(&self.key(key))
+    }
+
+    // dependencies keep their own keys, they may come from any scope
+    pub fn computed<D, T>(
+        &self,
+        key: &str,
+        dependencies: D,
+        compute: impl Fn(<D::Dependencies as Dependencies>::Values) -> T + Send + Sync + 'static,
+    ) -> DataHandle<T>
+    where
+        D: IntoDependencies,
+        T: Synchronizable,
+    {
+        self.poca.computed(&self.key(key), dependencies, compute)
+    }
+
+    pub fn collection<K, V>(&self, key: &str) -> CollectionHandle<K, V>
+    where
+        K: Display + Eq + Hash + Clone + Send + Sync + 'static,
+        V: Synchronizable,
+    {
+        self.poca.collection(&self.key(key))
+    }
+
+    pub fn event<P>(&self, key: &str, handler: impl Fn(P) + Send + Sync + 'static) -> Subscription
+    where
+        P: DeserializeOwned + Send + 'static,
+    {
+        self.poca.event(&self.key(key), handler)
+    }
+
+    pub fn emit<P: Synchronizable>(&self, key: &str, payload: P) {
+        self.poca.emit(&self.key(key), payload)
+    }
+
+    pub fn rpc<Req, Resp, E>(
+        &self,
+        key: &str,
+        handler: impl Fn(Req) -> Result<Resp, E> + Send + Sync + 'static,
+    ) -> Subscription
+    where
+        Req: DeserializeOwned + Send + 'static,
+        Resp: Synchronizable,
+        E: Display,
+    {
+        self.poca.rpc(&self.key(key), handler)
+    }
+
+    pub fn rpc_async<Req, Resp, E, Fut>(
+        &self,
+        key: &str,
+        handler: impl Fn(Req) -> Fut + Send + Sync + 'static,
+    ) -> Subscription
+    where
+        Req: DeserializeOwned + Send + 'static,
+        Resp: Synchronizable,
+        E: Display,
+        Fut: Future<Output = Result<Resp, E>> + Send + 'static,
+    {
+        self.poca.rpc_async(&self.key(key), handler)
+    }
+}
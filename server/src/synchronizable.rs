@@ -2,10 +2,15 @@ use std::{any::Any, fmt::Debug};
 
 use dyn_clone::DynClone;
 use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+
+use crate::error::Error;
 
 pub trait DataToAny: 'static {
     fn as_any(&self) -> &dyn Any;
-    fn to_any(self) -> Box<dyn Any + Send + Sync>;
+    fn to_any(self) -> Box<dyn Any + Send + Sync>
+    where
+        Self: Sized;
 }
 
 impl<T: 'static + Send + Sync> DataToAny for T {
@@ -23,9 +28,12 @@ pub trait SynchronizableClone {
     fn clone_synchronizable(&self) -> Box<dyn Synchronizable>;
 }
 
-pub trait Synchronizable: 'static + Sync + Send + Debug + DynClone + SynchronizableClone {
-    fn serialize(&self) -> String;
-    fn deserialize(&self, data: &str) -> Box<dyn Synchronizable>;
+pub trait Synchronizable:
+    'static + Sync + Send + Debug + DynClone + SynchronizableClone + DataToAny
+{
+    // the key is only used to report errors
+    fn serialize(&self, key: &str) -> Result<Value, Error>;
+    fn deserialize(&self, key: &str, value: Value) -> Result<Box<dyn Synchronizable>, Error>;
 }
 
 impl<T> SynchronizableClone for T
@@ -45,14 +53,19 @@ impl<T> Synchronizable for T
 where
     T: 'static + Sync + Send + Debug + Clone + Serialize + DeserializeOwned,
 {
-    fn serialize(&self) -> String {
-        //TODO: switch to no_proto for ser/de
-        serde_json::to_string(self).unwrap()
+    fn serialize(&self, key: &str) -> Result<Value, Error> {
+        serde_json::to_value(self).map_err(|source| Error::Serialize {
+            key: key.to_string(),
+            source,
+        })
     }
 
-    fn deserialize(&self, data: &str) -> Box<dyn Synchronizable> {
-        let data: T = serde_json::from_str(data).unwrap();
-        Box::new(data)
+    fn deserialize(&self, key: &str, value: Value) -> Result<Box<dyn Synchronizable>, Error> {
+        let data: T = serde_json::from_value(value).map_err(|source| Error::Deserialize {
+            key: key.to_string(),
+            source,
+        })?;
+        Ok(Box::new(data))
     }
 }
 
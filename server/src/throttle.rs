@@ -0,0 +1,212 @@
+use std::{collections::HashMap, time::Duration};
+
+use tokio::time::Instant;
+use warp::ws;
+
+// limits how often updates of one key are sent to each client,
+// held back updates are dropped in favour of the latest one
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Throttle {
+    // at most one update per interval, the first one is sent right away
+    Interval(Duration),
+    // only sent once the key has not changed for the whole duration
+    Debounce(Duration),
+}
+
+// the latest held back frame of a key
+struct Pending {
+    frame: ws::Message,
+    version: u64,
+    due: Instant,
+}
+
+struct KeyState {
+    throttle: Throttle,
+    last_sent: Option<Instant>,
+    pending: Option<Pending>,
+}
+
+impl KeyState {
+    // false once the key neither holds a frame back nor limits the next one
+    fn active(&self, now: Instant) -> bool {
+        match (self.throttle, self.last_sent) {
+            _ if self.pending.is_some() => true,
+            (Throttle::Interval(interval), Some(last_sent)) => now < last_sent + interval,
+            _ => false,
+        }
+    }
+}
+
+// per connection state of the throttled keys
+#[derive(Default)]
+pub struct Throttler {
+    keys: HashMap<String, KeyState>,
+}
+
+impl Throttler {
+    // returns the frame if it may be sent right away, otherwise holds it until it is due
+    pub fn push(
+        &mut self,
+        key: String,
+        version: u64,
+        throttle: Throttle,
+        frame: ws::Message,
+        now: Instant,
+    ) -> Option<ws::Message> {
+        self.keys.retain(|_, state| state.active(now));
+        let state = self.keys.entry(key).or_insert(KeyState {
+            throttle,
+            last_sent: None,
+            pending: None,
+        });
+        state.throttle = throttle;
+        let due = match throttle {
+            Throttle::Interval(interval) => match state.last_sent {
+                Some(last_sent) if now < last_sent + interval => last_sent + interval,
+                _ => {
+                    state.last_sent = Some(now);
+                    state.pending = None;
+                    return Some(frame);
+                }
+            },
+            Throttle::Debounce(quiet) => now + quiet,
+        };
+        state.pending = Some(Pending {
+            frame,
+            version,
+            due,
+        });
+        None
+    }
+
+    // drops the held back frame of a key once a frame at least as new went out
+    // another way, None for a removed key
+    pub fn supersede(&mut self, key: &str, version: Option<u64>) {
+        if let Some(state) = self.keys.get_mut(key) {
+            let superseded = match (&state.pending, version) {
+                (Some(pending), Some(version)) => pending.version <= version,
+                (pending, None) => pending.is_some(),
+                (None, _) => false,
+            };
+            if superseded {
+                state.pending = None;
+            }
+        }
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.keys
+            .values()
+            .filter_map(|state| state.pending.as_ref().map(|pending| pending.due))
+            .min()
+    }
+
+    pub fn take_due(&mut self, now: Instant) -> Vec<ws::Message> {
+        let mut due = Vec::new();
+        for state in self.keys.values_mut() {
+            if matches!(&state.pending, Some(pending) if pending.due <= now) {
+                let pending = state.pending.take().unwrap();
+                state.last_sent = Some(now);
+                due.push(pending.frame);
+            }
+        }
+        self.keys.retain(|_, state| state.active(now));
+        due
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "key";
+
+    fn frame(value: u64) -> ws::Message {
+        ws::Message::text(value.to_string())
+    }
+
+    fn millis(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn interval_sends_first_and_holds_latest() {
+        let mut throttler = Throttler::default();
+        let throttle = Throttle::Interval(millis(100));
+        let start = Instant::now();
+        let push = |throttler: &mut Throttler, version, at| {
+            throttler.push(
+                KEY.to_string(),
+                version,
+                throttle,
+                frame(version),
+                start + at,
+            )
+        };
+        assert_eq!(push(&mut throttler, 1, millis(0)), Some(frame(1)));
+        assert_eq!(push(&mut throttler, 2, millis(10)), None);
+        assert_eq!(push(&mut throttler, 3, millis(20)), None);
+        assert_eq!(throttler.next_deadline(), Some(start + millis(100)));
+        assert!(throttler.take_due(start + millis(50)).is_empty());
+        assert_eq!(throttler.take_due(start + millis(100)), vec![frame(3)]);
+        assert_eq!(throttler.next_deadline(), None);
+        // the interval starts again with the held back frame
+        assert_eq!(push(&mut throttler, 4, millis(150)), None);
+        // a frame sent right away replaces the one held back
+        assert_eq!(push(&mut throttler, 5, millis(210)), Some(frame(5)));
+        assert!(throttler.take_due(start + millis(250)).is_empty());
+    }
+
+    #[test]
+    fn debounce_waits_for_quiet() {
+        let mut throttler = Throttler::default();
+        let throttle = Throttle::Debounce(millis(50));
+        let start = Instant::now();
+        let push = |throttler: &mut Throttler, version, at| {
+            throttler.push(
+                KEY.to_string(),
+                version,
+                throttle,
+                frame(version),
+                start + at,
+            )
+        };
+        assert_eq!(push(&mut throttler, 1, millis(0)), None);
+        assert_eq!(throttler.next_deadline(), Some(start + millis(50)));
+        assert_eq!(push(&mut throttler, 2, millis(30)), None);
+        assert_eq!(throttler.next_deadline(), Some(start + millis(80)));
+        assert!(throttler.take_due(start + millis(60)).is_empty());
+        assert_eq!(throttler.take_due(start + millis(80)), vec![frame(2)]);
+        assert_eq!(throttler.next_deadline(), None);
+    }
+
+    #[test]
+    fn newer_frames_drop_held_back_frame() {
+        let mut throttler = Throttler::default();
+        let throttle = Throttle::Debounce(millis(50));
+        let start = Instant::now();
+        throttler.push(KEY.to_string(), 2, throttle, frame(2), start);
+        throttler.supersede(KEY, Some(1));
+        assert!(throttler.next_deadline().is_some());
+        throttler.supersede(KEY, Some(2));
+        assert_eq!(throttler.next_deadline(), None);
+
+        throttler.push(KEY.to_string(), 3, throttle, frame(3), start);
+        throttler.supersede(KEY, None);
+        assert!(throttler.take_due(start + millis(50)).is_empty());
+    }
+
+    #[test]
+    fn idle_keys_are_dropped() {
+        let mut throttler = Throttler::default();
+        let throttle = Throttle::Interval(millis(100));
+        let start = Instant::now();
+        throttler.push("a".to_string(), 1, throttle, frame(1), start);
+        throttler.push("b".to_string(), 1, throttle, frame(1), start + millis(50));
+        assert_eq!(throttler.keys.len(), 2);
+        throttler.push("c".to_string(), 1, throttle, frame(1), start + millis(120));
+        assert_eq!(throttler.keys.len(), 2);
+        throttler.take_due(start + millis(500));
+        assert!(throttler.keys.is_empty());
+    }
+}
@@ -0,0 +1,81 @@
+use std::sync::{atomic::Ordering, Arc};
+
+use crate::{
+    data_handle::{self, DataHandle},
+    message::Message,
+    poca::{BroadcastSender, DataElement, Revision, Store},
+    synchronizable::Synchronizable,
+};
+
+// writes collected by `Poca::transaction`, nothing is visible before the commit
+#[derive(Default)]
+pub struct Transaction {
+    writes: Vec<(String, DataElement, Box<dyn Synchronizable>)>,
+}
+
+impl Transaction {
+    // a later write to the same element replaces the earlier one, handles of a
+    // replaced key refer to another element than the key's current handles
+    pub fn set<T: Synchronizable>(&mut self, handle: &DataHandle<T>, value: T) {
+        let element = handle.data_element();
+        match self
+            .writes
+            .iter_mut()
+            .find(|(_, each, _)| Arc::ptr_eq(each, element))
+        {
+            Some((_, _, data)) => *data = Box::new(value),
+            None => self.writes.push((
+                handle.get_key().to_string(),
+                element.clone(),
+                Box::new(value),
+            )),
+        }
+    }
+
+    // commits all writes under the store lock so snapshots never see half of them,
+    // returns the changed elements for running their handlers
+    pub(crate) fn commit(
+        self,
+        store: &Store,
+        revision: &Revision,
+        sender: &BroadcastSender,
+    ) -> Vec<DataElement> {
+        if self.writes.is_empty() {
+            return Vec::new();
+        }
+        let _store_lock = store.lock();
+        let mut guards: Vec<_> = self
+            .writes
+            .iter()
+            .map(|(_, element, _)| element.write())
+            .collect();
+        // writes skipped as unchanged take no part in the commit
+        let changed: Vec<_> = self
+            .writes
+            .iter()
+            .zip(guards.iter())
+            .map(|((_, _, data), guard)| !data_handle::unchanged(guard, data.as_ref()))
+            .collect();
+        let revision = revision.fetch_add(1, Ordering::SeqCst) + 1;
+        let updates: Vec<_> = self
+            .writes
+            .iter()
+            .zip(guards.iter_mut())
+            .zip(changed.iter())
+            .filter(|(_, changed)| **changed)
+            .filter_map(|(((key, _, data), guard), _)| {
+                data_handle::write(key, guard, data.clone(), revision)
+            })
+            .collect();
+        if !updates.is_empty() {
+            sender.send(Message::Batch { revision, updates }).ok();
+        }
+        drop(guards);
+        self.writes
+            .into_iter()
+            .zip(changed)
+            .filter(|(_, changed)| *changed)
+            .map(|((_, element, _), _)| element)
+            .collect()
+    }
+}
@@ -1,121 +1,807 @@
-use std::ops::Deref;
+use std::{
+    slice,
+    sync::{atomic::Ordering, Arc},
+};
 
-use futures_util::pin_mut;
-use tokio_stream::{wrappers::BroadcastStream, StreamExt};
+use futures_util::{pin_mut, SinkExt};
+use json_patch::Patch;
+use parking_lot::RwLock;
+use serde_json::Value;
+use tokio::{
+    sync::mpsc,
+    time::{self, Instant},
+};
+use tokio_stream::{
+    wrappers::{errors::BroadcastStreamRecvError, BroadcastStream, UnboundedReceiverStream},
+    StreamExt,
+};
 use warp::ws::{self, WebSocket};
 
 use crate::{
-    event_handler::EventHandlerStore,
-    message::{Message, WSMessage, WSMessageType},
-    poca::{BroadcastReceiver, BroadcastSender, Store},
+    codec::{Codec, SUPPORTED_CODECS},
+    data_handle,
+    error::Error,
+    event_handler::{EventHandlerStore, RpcHandlerStore},
+    message::{
+        ErrorCode, Hello, Message, SnapshotEntry, WSError, WSMessage, WSMessageType, Welcome,
+        PROTOCOL_VERSION,
+    },
+    patch,
+    poca::{
+        BroadcastReceiver, BroadcastSender, ClientId, ClientSender, ClientStore, DataElement,
+        DataElementInner, Revision, Store, Validator,
+    },
+    synchronizable::Synchronizable,
+    throttle::{Throttle, Throttler},
 };
 
-pub async fn websocket_handler<'a>(
-    websocket: WebSocket,
-    store: Store,
-    event_handler_store: EventHandlerStore,
-    broadcast_receiver: BroadcastReceiver,
-    broadcast_sender: BroadcastSender,
-) {
-    let (ws_sender, ws_receiver) = futures_util::StreamExt::split(websocket);
-
-    //TODO: handshake, but let's skip it until basic frontend is done
-
-    let broadcast_stream = BroadcastStream::from(broadcast_receiver);
-    let broadcast_dealer = futures_util::StreamExt::forward(
-        broadcast_stream.filter_map(|message| {
-            match message {
-                Ok(inner) => match inner {
-                    Message::Set { key, data } => Some(Ok(ws::Message::text(
-                        serde_json::to_string(&WSMessage {
-                            message_type: WSMessageType::Set,
-                            key: Some(key),
-                            data: Some(data.serialize()),
-                        })
-                        .unwrap(),
-                    ))),
-                    Message::Get { key, data } => Some(Ok(ws::Message::text(
-                        serde_json::to_string(&WSMessage {
-                            message_type: WSMessageType::Get,
-                            key: Some(key),
-                            data: Some(data.serialize()),
-                        })
-                        .unwrap(),
-                    ))),
-                },
-                Err(error) => {
-                    //TODO: uniformed logging
-                    println!("Error when receiving from broadcast channel: {}", error);
-                    None
-                }
+// everything a connection shares with the rest of the app
+pub struct Connection {
+    pub client_id: ClientId,
+    pub store: Store,
+    pub revision: Revision,
+    pub event_handler_store: EventHandlerStore,
+    pub rpc_handler_store: RpcHandlerStore,
+    pub broadcast_sender: BroadcastSender,
+    pub clients: ClientStore,
+    // scopes this client subscribed to, it receives updates of all keys while empty
+    pub scopes: Arc<RwLock<Vec<String>>>,
+}
+
+fn to_ws_message(message: Message, codec: Codec) -> ws::Message {
+    encode_message(&to_wire(message), codec)
+}
+
+fn to_wire(message: Message) -> WSMessage {
+    match message {
+        Message::Set {
+            key,
+            data,
+            revision,
+            version,
+            ..
+        } => match data.serialize(&key) {
+            Ok(data) => WSMessage {
+                key: Some(key),
+                data: Some(data),
+                revision: Some(revision),
+                version: Some(version),
+                ..WSMessage::new(WSMessageType::Set)
+            },
+            Err(error) => error_message(None, error),
+        },
+        Message::Patch {
+            key,
+            patch,
+            revision,
+            version,
+        } => WSMessage {
+            key: Some(key),
+            data: Some(serde_json::to_value(&patch).unwrap()),
+            revision: Some(revision),
+            version: Some(version),
+            ..WSMessage::new(WSMessageType::Patch)
+        },
+        Message::Get {
+            id,
+            key,
+            data,
+            version,
+        } => match data.serialize(&key) {
+            Ok(data) => WSMessage {
+                id,
+                key: Some(key),
+                data: Some(data),
+                version: Some(version),
+                ..WSMessage::new(WSMessageType::Get)
+            },
+            Err(error) => error_message(id, error),
+        },
+        Message::Snapshot {
+            id,
+            key,
+            revision,
+            entries,
+        } => WSMessage {
+            id,
+            key,
+            data: Some(serde_json::to_value(&entries).unwrap()),
+            revision: Some(revision),
+            ..WSMessage::new(WSMessageType::Snapshot)
+        },
+        Message::Removed { key, revision } => WSMessage {
+            key: Some(key),
+            revision: Some(revision),
+            ..WSMessage::new(WSMessageType::Removed)
+        },
+        Message::Batch { revision, updates } => WSMessage {
+            revision: Some(revision),
+            data: Some(Value::Array(
+                updates
+                    .into_iter()
+                    .map(|update| serde_json::to_value(to_wire(update)).unwrap())
+                    .collect(),
+            )),
+            ..WSMessage::new(WSMessageType::Batch)
+        },
+        Message::Emit { key, data } => match data.serialize(&key) {
+            Ok(data) => WSMessage {
+                key: Some(key),
+                data: Some(data),
+                ..WSMessage::new(WSMessageType::Emit)
+            },
+            Err(error) => error_message(None, error),
+        },
+        Message::Reply { id, key, data } => match data.serialize(&key) {
+            Ok(data) => WSMessage {
+                id,
+                key: Some(key),
+                data: Some(data),
+                ..WSMessage::new(WSMessageType::Reply)
+            },
+            Err(error) => error_message(id, error),
+        },
+        Message::Error { id, key, error } => WSMessage {
+            id,
+            key,
+            error: Some(error),
+            ..WSMessage::new(WSMessageType::Error)
+        },
+    }
+}
+
+fn error_message(id: Option<u64>, error: Error) -> WSMessage {
+    //TODO: uniformed logging
+    println!("{}", error);
+    WSMessage {
+        id,
+        key: Some(error.key().to_string()),
+        error: Some(error.into()),
+        ..WSMessage::new(WSMessageType::Error)
+    }
+}
+
+fn encode_message(message: &WSMessage, codec: Codec) -> ws::Message {
+    ws::Message::binary(codec.encode(message))
+}
+
+fn parse_message(message: &ws::Message, codec: Codec) -> Result<WSMessage, WSError> {
+    //TODO: uniformed logging
+    println!("Got Websocket message: {:?}", message);
+    codec
+        .decode(message.as_bytes())
+        .map_err(|error| WSError::new(ErrorCode::MalformedMessage, error.to_string()))
+}
+
+// Hello and Welcome are always JSON, the negotiated codec applies afterwards
+fn handshake(message: &WSMessage, connection: &Connection) -> Result<(Welcome, Codec), WSError> {
+    if message.message_type != WSMessageType::Hello {
+        return Err(WSError::new(
+            ErrorCode::HandshakeRequired,
+            "The first message must be a Hello",
+        ));
+    }
+    let data = message
+        .data
+        .as_ref()
+        .ok_or_else(|| WSError::new(ErrorCode::MissingData, "Hello without data"))?;
+    let hello: Hello = serde_json::from_value(data.clone())
+        .map_err(|error| WSError::new(ErrorCode::MalformedMessage, error.to_string()))?;
+    if hello.version != PROTOCOL_VERSION {
+        return Err(WSError::new(
+            ErrorCode::VersionMismatch,
+            format!(
+                "Client speaks protocol version {}, server speaks version {}",
+                hello.version, PROTOCOL_VERSION
+            ),
+        ));
+    }
+    // the client lists encodings in order of preference
+    let codec = hello
+        .encodings
+        .iter()
+        .find_map(|encoding| Codec::from_name(encoding))
+        .ok_or_else(|| {
+            let supported = SUPPORTED_CODECS.iter().map(Codec::name).collect::<Vec<_>>();
+            WSError::new(
+                ErrorCode::UnsupportedEncoding,
+                format!("Supported encodings are {:?}", supported),
+            )
+        })?;
+    let welcome = Welcome {
+        version: PROTOCOL_VERSION,
+        session_id: connection.client_id,
+        encoding: codec.name().to_string(),
+        keys: connection.store.lock().keys().cloned().collect(),
+        events: connection
+            .event_handler_store
+            .read()
+            .keys()
+            .cloned()
+            .collect(),
+        rpcs: connection
+            .rpc_handler_store
+            .read()
+            .keys()
+            .cloned()
+            .collect(),
+    };
+    Ok((welcome, codec))
+}
+
+// the keys a frame brings clients up to date on with their versions, None for removals
+fn updated_keys(message: &Message) -> Vec<(String, Option<u64>)> {
+    match message {
+        Message::Set { key, version, .. }
+        | Message::Patch { key, version, .. }
+        | Message::Get { key, version, .. } => vec![(key.clone(), Some(*version))],
+        Message::Removed { key, .. } => vec![(key.clone(), None)],
+        Message::Snapshot { entries, .. } => entries
+            .iter()
+            .map(|(key, entry)| (key.clone(), Some(entry.version)))
+            .collect(),
+        Message::Batch { updates, .. } => updates.iter().flat_map(updated_keys).collect(),
+        _ => Vec::new(),
+    }
+}
+
+// a frame on its way to one client
+struct Outgoing {
+    frame: ws::Message,
+    // the key, version and throttle of a Set that may be held back
+    throttled: Option<(String, u64, Throttle)>,
+    updated_keys: Vec<(String, Option<u64>)>,
+}
+
+impl Outgoing {
+    fn new(message: Message, codec: Codec) -> Self {
+        let throttled = match &message {
+            Message::Set {
+                key,
+                version,
+                throttle: Some(throttle),
+                ..
+            } => Some((key.clone(), *version, *throttle)),
+            _ => None,
+        };
+        Outgoing {
+            updated_keys: updated_keys(&message),
+            frame: to_ws_message(message, codec),
+            throttled,
+        }
+    }
+}
+
+// keys of a scope are the scope itself and everything below it,
+// an empty list of scopes means all keys
+fn in_scopes(key: &str, scopes: &[String]) -> bool {
+    scopes.is_empty()
+        || scopes
+            .iter()
+            .any(|scope| match key.strip_prefix(scope.as_str()) {
+                Some(rest) => rest.is_empty() || rest.starts_with('.') || rest.starts_with('/'),
+                None => false,
+            })
+}
+
+// drops the state updates outside of the scopes the client subscribed to,
+// events are not scoped
+fn filter_scopes(message: Message, scopes: &[String]) -> Option<Message> {
+    match message {
+        Message::Batch { revision, updates } => {
+            let updates: Vec<_> = updates
+                .into_iter()
+                .filter_map(|update| filter_scopes(update, scopes))
+                .collect();
+            if updates.is_empty() {
+                None
+            } else {
+                Some(Message::Batch { revision, updates })
             }
-        }),
-        ws_sender,
-    );
+        }
+        Message::Set { ref key, .. }
+        | Message::Patch { ref key, .. }
+        | Message::Removed { ref key, .. }
+            if !in_scopes(key, scopes) =>
+        {
+            None
+        }
+        message => Some(message),
+    }
+}
 
-    let ws_dealer = futures_util::TryStreamExt::try_for_each(ws_receiver, |message| {
-        //TODO: uniformed logging
-        //TODO: use bytes instead of string
-        let text = message.to_str().unwrap();
-        println!("Got Websocket message: {:?}", &text);
-        let message: WSMessage = serde_json::from_str(text).unwrap();
-        match message.message_type {
-            WSMessageType::Set => {
-                let key = message.key.unwrap();
-                let store_lock = store.lock();
-                let element_entry = store_lock.get(&key).unwrap();
-                let element = element_entry.deref();
-                let new_data;
-                {
-                    let handle = element.read();
-                    new_data = handle.data.deserialize(message.data.unwrap().as_str());
+// the revision is read before any element, so every write missing from the
+// snapshot is guaranteed to arrive later with a higher revision
+fn snapshot(
+    store: &Store,
+    revision: &Revision,
+    scopes: &[String],
+    id: Option<u64>,
+    key: Option<String>,
+) -> Message {
+    let store_lock = store.lock();
+    let current_revision = revision.load(Ordering::SeqCst);
+    let entries = store_lock
+        .iter()
+        .filter(|(key, _)| in_scopes(key, scopes))
+        .filter_map(|(key, element)| {
+            let handle = element.read();
+            if !handle.access.readable() {
+                return None;
+            }
+            // unserializable values are left out, a Get on them reports the error
+            let entry = SnapshotEntry {
+                data: handle.data.serialize(key).ok()?,
+                version: handle.version,
+            };
+            Some((key.clone(), entry))
+        })
+        .collect();
+    Message::Snapshot {
+        id,
+        key,
+        revision: current_revision,
+        entries,
+    }
+}
+
+fn unknown_key(key: &str) -> WSError {
+    WSError::new(
+        ErrorCode::UnknownKey,
+        format!("Element with key {} cannot be found", key),
+    )
+}
+
+fn find_element(store: &Store, key: &str) -> Result<DataElement, WSError> {
+    store
+        .lock()
+        .get(key)
+        .cloned()
+        .ok_or_else(|| unknown_key(key))
+}
+
+// the current value is resent to a client whose write was rejected,
+// so it can drop its local change
+fn reject(
+    key: &str,
+    element: &DataElementInner,
+    client_sender: &ClientSender,
+    error: WSError,
+) -> Result<(), WSError> {
+    // sent like a reply to Get, clients take it even if they hold the same version
+    if element.access.readable() {
+        client_sender
+            .send(Message::Get {
+                id: None,
+                key: key.to_string(),
+                data: element.data.clone(),
+                version: element.version,
+            })
+            .ok();
+    }
+    Err(error)
+}
+
+fn validate(
+    key: &str,
+    validators: &[Validator],
+    new_data: &dyn Synchronizable,
+    current: &dyn Synchronizable,
+) -> Result<(), WSError> {
+    for validator in validators {
+        validator(new_data, current).map_err(|reason| {
+            WSError::new(
+                ErrorCode::ValidationFailed,
+                format!("Rejected value for key {}: {}", key, reason),
+            )
+        })?;
+    }
+    Ok(())
+}
+
+// commits a Set (data) or Patch from a client, returns false if it changed nothing
+fn client_write(
+    key: &str,
+    element: &DataElement,
+    mut data: Option<Value>,
+    patch: Option<Patch>,
+    expected_version: Option<u64>,
+    connection: &Connection,
+    client_sender: &ClientSender,
+) -> Result<bool, WSError> {
+    let Connection {
+        revision,
+        broadcast_sender,
+        ..
+    } = connection;
+    // a Set does not depend on the current value, it is only decoded once
+    let mut decoded = None;
+    loop {
+        let (current, version, validators) = {
+            let handle = element.read();
+            // removed after it was looked up, clients were told to drop it
+            if handle.removed {
+                return Err(unknown_key(key));
+            }
+            if !handle.access.writable() {
+                let error = WSError::new(
+                    ErrorCode::Forbidden,
+                    format!("Element with key {} is read-only", key),
+                );
+                return reject(key, &handle, client_sender, error).map(|_| false);
+            }
+            if let Some(expected_version) = expected_version {
+                if expected_version != handle.version {
+                    let error = WSError::new(
+                        ErrorCode::VersionConflict,
+                        format!(
+                            "Expected version {} but element with key {} is at version {}",
+                            expected_version, key, handle.version
+                        ),
+                    );
+                    return reject(key, &handle, client_sender, error).map(|_| false);
                 }
-                {
-                    let mut handle = element.write();
-                    handle.data = new_data;
+            }
+            (
+                handle.data.clone(),
+                handle.version,
+                handle.validators.clone(),
+            )
+        };
+        let new_data = match (&patch, decoded.take(), data.take()) {
+            (Some(patch), _, _) => patch::apply(key, current.as_ref(), patch)?,
+            (None, Some(decoded), _) => decoded,
+            (None, None, Some(data)) => current.deserialize(key, data)?,
+            (None, None, None) => unreachable!(),
+        };
+        // validators run without the lock held, so they may read other handles
+        if let Err(error) = validate(key, &validators, new_data.as_ref(), current.as_ref()) {
+            return reject(key, &element.read(), client_sender, error).map(|_| false);
+        }
+        let mut handle = element.write();
+        // another write got in meanwhile, the checks and validators run again
+        if handle.version != version || handle.removed {
+            decoded = Some(new_data);
+            continue;
+        }
+        // nothing to send and no handlers to run
+        if data_handle::unchanged(&handle, new_data.as_ref()) {
+            return Ok(false);
+        }
+        let next_revision = revision.fetch_add(1, Ordering::SeqCst) + 1;
+        // the incoming patch is not passed on, deserializing may have dropped or
+        // filled in fields, so clients get a diff of what the server actually holds
+        if let Some(update) = data_handle::write(key, &mut handle, new_data, next_revision) {
+            broadcast_sender.send(update).ok();
+        }
+        return Ok(true);
+    }
+}
+
+fn require_key(message: &WSMessage) -> Result<&str, WSError> {
+    message
+        .key
+        .as_deref()
+        .ok_or_else(|| WSError::new(ErrorCode::MissingKey, "Message has no key"))
+}
+
+fn handle_message(
+    mut message: WSMessage,
+    connection: &Connection,
+    client_sender: &ClientSender,
+) -> Result<(), WSError> {
+    let Connection {
+        store,
+        revision,
+        event_handler_store,
+        rpc_handler_store,
+        scopes,
+        ..
+    } = connection;
+    match message.message_type {
+        WSMessageType::Set | WSMessageType::Patch => {
+            let data = message.data.take().ok_or_else(|| {
+                WSError::new(
+                    ErrorCode::MissingData,
+                    format!("{:?} without data", message.message_type),
+                )
+            })?;
+            let key = require_key(&message)?;
+            let (data, patch) = if message.message_type == WSMessageType::Patch {
+                // a patch only applies to the version it was computed against
+                if message.expected_version.is_none() {
+                    return Err(WSError::new(
+                        ErrorCode::MissingVersion,
+                        "Patch without expected_version",
+                    ));
                 }
+                let patch: Patch = serde_json::from_value(data).map_err(|error| {
+                    WSError::new(ErrorCode::MalformedMessage, error.to_string())
+                })?;
+                (None, Some(patch))
+            } else {
+                (Some(data), None)
+            };
+            let element = find_element(store, key)?;
+            if client_write(
+                key,
+                &element,
+                data,
+                patch,
+                message.expected_version,
+                connection,
+                client_sender,
+            )? {
                 //TODO: emit events
-                {
-                    let handle = element.read();
-                    for each in handle.on_change.deref() {
-                        let handler = each.deref();
-                        handler()
-                    }
-                }
+                data_handle::run_on_change(&element);
             }
-            WSMessageType::Get => {
-                let key = message.key.unwrap();
-                let data;
-                {
-                    let store_lock = store.lock();
-                    let element_entry = store_lock
-                        .get(&key)
-                        .expect(format!("Element with key {} cannot be found", key).as_str());
-                    let element = element_entry.deref();
-                    let handle = element.read();
-                    data = handle.data.serialize();
+        }
+        WSMessageType::Get => {
+            let key = require_key(&message)?;
+            let data;
+            let version;
+            {
+                let element = find_element(store, key)?;
+                let handle = element.read();
+                if !handle.access.readable() {
+                    return Err(WSError::new(
+                        ErrorCode::Forbidden,
+                        format!("Element with key {} is write-only", key),
+                    ));
                 }
-                broadcast_sender
-                    .send(Message::Get {
-                        key,
-                        data: Box::new(data),
-                    })
-                    .ok();
+                data = handle.data.clone();
+                version = handle.version;
             }
-            WSMessageType::Emit => {
-                let key = message.key.unwrap();
-                let lock = event_handler_store.read();
-                let handlers = lock
-                    .get(&key)
-                    .expect(format!("Event handler with key {} cannot be found", key).as_str());
-                for handler in handlers {
-                    handler();
+            client_sender
+                .send(Message::Get {
+                    id: message.id,
+                    key: key.to_string(),
+                    data,
+                    version,
+                })
+                .ok();
+        }
+        WSMessageType::Emit => {
+            let payload = message.data.take().unwrap_or(Value::Null);
+            let key = require_key(&message)?;
+            let handlers: Vec<_> = event_handler_store
+                .read()
+                .get(key)
+                .ok_or_else(|| {
+                    WSError::new(
+                        ErrorCode::UnknownEvent,
+                        format!("Event handler with key {} cannot be found", key),
+                    )
+                })?
+                .values()
+                .cloned()
+                .collect();
+            let calls = handlers
+                .iter()
+                .map(|handler| handler(&payload))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|error| {
+                    WSError::new(
+                        ErrorCode::InvalidPayload,
+                        format!("Invalid payload for event {}: {}", key, error),
+                    )
+                })?;
+            for call in calls {
+                call();
+            }
+        }
+        WSMessageType::Snapshot => {
+            let scope = message.key.take();
+            let scopes: Vec<_> = scope.iter().cloned().collect();
+            client_sender
+                .send(snapshot(store, revision, &scopes, message.id, scope))
+                .ok();
+        }
+        WSMessageType::Subscribe => {
+            let scope = require_key(&message)?.to_string();
+            {
+                let mut scopes = scopes.write();
+                if !scopes.contains(&scope) {
+                    scopes.push(scope.clone());
                 }
             }
-            _ => {
-                todo!("handle other message types")
+            // taken after subscribing so no update in between is missed
+            let key = Some(scope.clone());
+            client_sender
+                .send(snapshot(
+                    store,
+                    revision,
+                    slice::from_ref(&scope),
+                    message.id,
+                    key,
+                ))
+                .ok();
+        }
+        WSMessageType::Unsubscribe => {
+            let scope = require_key(&message)?;
+            scopes.write().retain(|each| each != scope);
+        }
+        WSMessageType::Call => {
+            let request = message.data.take().unwrap_or(Value::Null);
+            let key = require_key(&message)?;
+            let handler = rpc_handler_store.read().get(key).cloned().ok_or_else(|| {
+                WSError::new(
+                    ErrorCode::UnknownRpc,
+                    format!("Rpc with key {} cannot be found", key),
+                )
+            })?;
+            let call = handler(&request).map_err(|error| {
+                WSError::new(
+                    ErrorCode::InvalidPayload,
+                    format!("Invalid request for rpc {}: {}", key, error),
+                )
+            })?;
+            let id = message.id;
+            let key = key.to_string();
+            let client_sender = client_sender.clone();
+            // async handlers must not hold up the other messages of this client
+            tokio::spawn(async move {
+                let reply = match call.await {
+                    Ok(data) => Message::Reply { id, key, data },
+                    Err(reason) => Message::Error {
+                        id,
+                        key: Some(key),
+                        error: WSError::new(ErrorCode::RpcFailed, reason),
+                    },
+                };
+                client_sender.send(reply).ok();
+            });
+        }
+        other => {
+            return Err(WSError::new(
+                ErrorCode::UnsupportedMessageType,
+                format!("Clients cannot send {:?} messages", other),
+            ));
+        }
+    }
+    Ok(())
+}
+
+pub async fn websocket_handler(
+    websocket: WebSocket,
+    connection: Connection,
+    broadcast_receiver: BroadcastReceiver,
+) {
+    let (mut ws_sender, mut ws_receiver) = futures_util::StreamExt::split(websocket);
+
+    let hello = loop {
+        match ws_receiver.next().await {
+            Some(Ok(message)) if message.is_ping() || message.is_pong() => continue,
+            Some(Ok(message)) if !message.is_close() => break message,
+            _ => return,
+        }
+    };
+    let hello = parse_message(&hello, Codec::Json);
+    let id = hello.as_ref().ok().and_then(|hello| hello.id);
+    let welcome = hello.and_then(|hello| handshake(&hello, &connection));
+    let codec = match welcome {
+        Ok((welcome, codec)) => {
+            let reply = WSMessage {
+                id,
+                data: Some(serde_json::to_value(&welcome).unwrap()),
+                ..WSMessage::new(WSMessageType::Welcome)
+            };
+            let snapshot = to_wire(snapshot(
+                &connection.store,
+                &connection.revision,
+                &[],
+                None,
+                None,
+            ));
+            if ws_sender
+                .send(encode_message(&reply, Codec::Json))
+                .await
+                .is_err()
+                || ws_sender
+                    .send(encode_message(&snapshot, codec))
+                    .await
+                    .is_err()
+            {
+                return;
+            }
+            codec
+        }
+        Err(error) => {
+            //TODO: uniformed logging
+            println!("Rejected Websocket handshake: {}", error.message);
+            let reply = to_ws_message(
+                Message::Error {
+                    id,
+                    key: None,
+                    error,
+                },
+                Codec::Json,
+            );
+            ws_sender.send(reply).await.ok();
+            ws_sender.close().await.ok();
+            return;
+        }
+    };
+
+    // messages meant only for this client, e.g. replies to Get
+    let (client_sender, client_receiver) = mpsc::unbounded_channel();
+    connection
+        .clients
+        .lock()
+        .insert(connection.client_id, client_sender.clone());
+
+    let store = connection.store.clone();
+    let revision = connection.revision.clone();
+    let scopes = connection.scopes.clone();
+    let broadcast_stream =
+        BroadcastStream::from(broadcast_receiver).filter_map(move |message| match message {
+            Ok(message) => {
+                let message = filter_scopes(message, &scopes.read())?;
+                Some(Outgoing::new(message, codec))
+            }
+            // the skipped updates are gone, a fresh snapshot brings the client back in sync
+            Err(BroadcastStreamRecvError::Lagged(skipped)) => {
+                //TODO: uniformed logging
+                println!(
+                    "Client lagged {} messages behind, resending snapshot",
+                    skipped
+                );
+                let snapshot = snapshot(&store, &revision, &scopes.read(), None, None);
+                Some(Outgoing::new(snapshot, codec))
+            }
+        });
+    let client_stream = UnboundedReceiverStream::new(client_receiver)
+        .map(move |message| Outgoing::new(message, codec));
+    let broadcast_dealer = async move {
+        let outgoing = broadcast_stream.merge(client_stream);
+        pin_mut!(outgoing);
+        let mut throttler = Throttler::default();
+        loop {
+            let deadline = throttler.next_deadline();
+            let due = time::sleep_until(deadline.unwrap_or_else(Instant::now));
+            let frames = tokio::select! {
+                next = outgoing.next() => match next {
+                    Some(Outgoing { frame, throttled: Some((key, version, throttle)), .. }) => {
+                        throttler
+                            .push(key, version, throttle, frame, Instant::now())
+                            .into_iter()
+                            .collect()
+                    }
+                    // a held back Set must not follow a newer frame for its key
+                    Some(Outgoing { frame, updated_keys, .. }) => {
+                        for (key, version) in updated_keys {
+                            throttler.supersede(&key, version);
+                        }
+                        vec![frame]
+                    }
+                    None => break,
+                },
+                _ = due, if deadline.is_some() => throttler.take_due(Instant::now()),
+            };
+            for frame in frames {
+                if ws_sender.send(frame).await.is_err() {
+                    return;
+                }
             }
         }
+    };
+
+    let ws_dealer = futures_util::TryStreamExt::try_for_each(ws_receiver, |message| {
+        if message.is_close() || message.is_ping() || message.is_pong() {
+            return futures_util::future::ok(());
+        }
+        let (id, key, result) = match parse_message(&message, codec) {
+            Ok(message) => (
+                message.id,
+                message.key.clone(),
+                handle_message(message, &connection, &client_sender),
+            ),
+            Err(error) => (None, None, Err(error)),
+        };
+        if let Err(error) = result {
+            println!("Error when handling Websocket message: {}", error.message);
+            client_sender.send(Message::Error { id, key, error }).ok();
+        }
         futures_util::future::ok(())
     });
 
@@ -125,4 +811,492 @@ pub async fn websocket_handler<'a>(
         _ = broadcast_dealer => {},
         _ = ws_dealer => {},
     }
+
+    connection.clients.lock().remove(&connection.client_id);
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::{Deserialize, Serialize};
+    use serde_json::json;
+    use tokio::sync::broadcast;
+
+    use super::*;
+    use crate::{
+        data_handle::DataHandle,
+        event_handler::{RpcCall, RpcHandler},
+        poca::{insert_element, remove_element, Access},
+    };
+
+    struct Fixture {
+        connection: Connection,
+        client_sender: ClientSender,
+        client_receiver: mpsc::UnboundedReceiver<Message>,
+        broadcast_receiver: BroadcastReceiver,
+    }
+
+    fn fixture() -> Fixture {
+        let (broadcast_sender, broadcast_receiver) = broadcast::channel(32);
+        let (client_sender, client_receiver) = mpsc::unbounded_channel();
+        Fixture {
+            connection: Connection {
+                client_id: 0,
+                store: Default::default(),
+                revision: Default::default(),
+                event_handler_store: Default::default(),
+                rpc_handler_store: Default::default(),
+                broadcast_sender,
+                clients: Default::default(),
+                scopes: Default::default(),
+            },
+            client_sender,
+            client_receiver,
+            broadcast_receiver,
+        }
+    }
+
+    impl Fixture {
+        // registering announces the key, tests only look at what follows
+        fn data<T: Synchronizable>(&mut self, key: &str, data: T, access: Access) -> DataHandle<T> {
+            let handle = insert_element(
+                &self.connection.store,
+                &self.connection.revision,
+                &self.connection.broadcast_sender,
+                key,
+                data,
+                access,
+                false,
+            );
+            while self.broadcast().is_some() {}
+            handle
+        }
+
+        fn send(&self, message: WSMessage) -> Result<(), WSError> {
+            handle_message(message, &self.connection, &self.client_sender)
+        }
+
+        // the next frame sent to this client only
+        fn reply(&mut self) -> Option<WSMessage> {
+            self.client_receiver.try_recv().ok().map(to_wire)
+        }
+
+        // the next frame sent to all clients
+        fn broadcast(&mut self) -> Option<WSMessage> {
+            self.broadcast_receiver.try_recv().ok().map(to_wire)
+        }
+    }
+
+    fn message(message_type: WSMessageType, key: &str, data: Option<Value>) -> WSMessage {
+        WSMessage {
+            id: Some(1),
+            key: Some(key.to_string()),
+            data,
+            ..WSMessage::new(message_type)
+        }
+    }
+
+    #[derive(Serialize, Deserialize, Clone, Debug)]
+    struct Form {
+        name: String,
+        #[serde(default)]
+        count: u32,
+    }
+
+    #[test]
+    fn patch_is_rebroadcast_as_server_diff() {
+        let mut fixture = fixture();
+        let form = Form {
+            name: "a".to_string(),
+            count: 1,
+        };
+        let handle = fixture.data("form", form, Access::ReadWrite);
+        let patch = json!([
+            { "op": "replace", "path": "/name", "value": "b" },
+            { "op": "add", "path": "/unknown", "value": 2 },
+        ]);
+        fixture
+            .send(WSMessage {
+                expected_version: Some(0),
+                ..message(WSMessageType::Patch, "form", Some(patch))
+            })
+            .unwrap();
+        assert_eq!(handle.get().name, "b");
+        assert!(fixture.reply().is_none());
+
+        // applying the broadcast to the old value gives what the server holds
+        let update = fixture.broadcast().unwrap();
+        assert_eq!(update.version, Some(1));
+        let mut value = json!({ "name": "a", "count": 1 });
+        match update.message_type {
+            WSMessageType::Patch => {
+                let patch = serde_json::from_value(update.data.unwrap()).unwrap();
+                json_patch::patch(&mut value, &patch).unwrap();
+            }
+            _ => value = update.data.unwrap(),
+        }
+        assert_eq!(value, json!({ "name": "b", "count": 1 }));
+    }
+
+    #[test]
+    fn patch_requires_expected_version() {
+        let mut fixture = fixture();
+        let _handle = fixture.data("list", vec![1, 2, 3], Access::ReadWrite);
+        let patch = json!([{ "op": "replace", "path": "/0", "value": 4 }]);
+        let error = fixture
+            .send(message(WSMessageType::Patch, "list", Some(patch)))
+            .unwrap_err();
+        assert_eq!(error.code, ErrorCode::MissingVersion);
+    }
+
+    #[test]
+    fn validators_may_read_handles() {
+        let mut fixture = fixture();
+        let limit = fixture.data("limit", 10, Access::ReadWrite);
+        let count = fixture.data("count", 0, Access::ReadWrite);
+        let count_clone = count.clone();
+        let count = count.validate(move |new, _| {
+            // reading the validated key itself deadlocked while the write lock was held
+            if *new > *limit.get() || *count_clone.get() < 0 {
+                return Err("Too large".to_string());
+            }
+            Ok(())
+        });
+        fixture
+            .send(message(WSMessageType::Set, "count", Some(json!(5))))
+            .unwrap();
+        assert_eq!(*count.get(), 5);
+        let error = fixture
+            .send(message(WSMessageType::Set, "count", Some(json!(11))))
+            .unwrap_err();
+        assert_eq!(error.code, ErrorCode::ValidationFailed);
+        assert_eq!(*count.get(), 5);
+        // the client is sent the value it failed to overwrite
+        let resend = fixture.reply().unwrap();
+        assert_eq!(resend.message_type, WSMessageType::Get);
+        assert_eq!(resend.data, Some(json!(5)));
+    }
+
+    #[test]
+    fn runtime_keys_are_announced() {
+        let mut fixture = fixture();
+        let store = fixture.connection.store.clone();
+        let revision = fixture.connection.revision.clone();
+        let sender = fixture.connection.broadcast_sender.clone();
+        let insert = |data, replace| {
+            insert_element(
+                &store,
+                &revision,
+                &sender,
+                "key",
+                data,
+                Access::ReadWrite,
+                replace,
+            )
+        };
+        let _handle = insert(1, false);
+        let announced = fixture.broadcast().unwrap();
+        assert_eq!(announced.message_type, WSMessageType::Set);
+        assert_eq!(announced.data, Some(json!(1)));
+
+        let _replaced = insert(2, true);
+        let removed = fixture.broadcast().unwrap();
+        assert_eq!(removed.message_type, WSMessageType::Removed);
+        let announced = fixture.broadcast().unwrap();
+        assert_eq!(announced.message_type, WSMessageType::Set);
+        assert_eq!(announced.data, Some(json!(2)));
+        assert!(announced.revision > removed.revision);
+
+        // write-only keys are never sent
+        let _secret = insert_element(
+            &store,
+            &revision,
+            &sender,
+            "secret",
+            3,
+            Access::WriteOnly,
+            false,
+        );
+        assert!(fixture.broadcast().is_none());
+    }
+
+    #[test]
+    fn set_on_removed_key_is_rejected() {
+        let mut fixture = fixture();
+        let handle = fixture.data("key", 1, Access::ReadWrite);
+        let element = handle.data_element().clone();
+        remove_element(
+            &fixture.connection.store,
+            &fixture.connection.revision,
+            &fixture.connection.broadcast_sender,
+            "key",
+            None,
+        );
+        // a Set that looked the element up before it was removed
+        let error = client_write(
+            "key",
+            &element,
+            Some(json!(2)),
+            None,
+            None,
+            &fixture.connection,
+            &fixture.client_sender,
+        )
+        .unwrap_err();
+        assert_eq!(error.code, ErrorCode::UnknownKey);
+        assert_eq!(*handle.get(), 1);
+    }
+
+    #[test]
+    fn scopes_filter_state_but_not_events() {
+        let scopes = vec!["todos".to_string()];
+        let set = |key: &str| Message::Set {
+            key: key.to_string(),
+            data: Box::new(1),
+            revision: 1,
+            version: 1,
+            throttle: None,
+        };
+        assert!(filter_scopes(set("todos/1"), &scopes).is_some());
+        assert!(filter_scopes(set("todosx"), &scopes).is_none());
+        assert!(filter_scopes(set("settings"), &scopes).is_none());
+        let emit = Message::Emit {
+            key: "saved".to_string(),
+            data: Box::new(()),
+        };
+        assert!(filter_scopes(emit, &scopes).is_some());
+    }
+
+    #[test]
+    fn malformed_frames_are_reported() {
+        let error =
+            parse_message(&ws::Message::binary("{\"message_type\":"), Codec::Json).unwrap_err();
+        assert_eq!(error.code, ErrorCode::MalformedMessage);
+    }
+
+    #[test]
+    fn failures_are_error_frames() {
+        let mut fixture = fixture();
+        let _handle = fixture.data("key", 1, Access::ReadWrite);
+        let error = fixture
+            .send(message(WSMessageType::Get, "missing", None))
+            .unwrap_err();
+        assert_eq!(error.code, ErrorCode::UnknownKey);
+        let error = fixture
+            .send(message(WSMessageType::Set, "key", None))
+            .unwrap_err();
+        assert_eq!(error.code, ErrorCode::MissingData);
+        let error = fixture
+            .send(WSMessage {
+                key: None,
+                ..message(WSMessageType::Set, "key", Some(json!(2)))
+            })
+            .unwrap_err();
+        assert_eq!(error.code, ErrorCode::MissingKey);
+        let error = fixture
+            .send(message(WSMessageType::Set, "key", Some(json!("two"))))
+            .unwrap_err();
+        assert_eq!(error.code, ErrorCode::InvalidData);
+        let error = fixture
+            .send(message(WSMessageType::Emit, "missing", None))
+            .unwrap_err();
+        assert_eq!(error.code, ErrorCode::UnknownEvent);
+        let error = fixture
+            .send(message(WSMessageType::Welcome, "key", None))
+            .unwrap_err();
+        assert_eq!(error.code, ErrorCode::UnsupportedMessageType);
+
+        // the frame carries the id and key of the failed message
+        let frame = to_wire(Message::Error {
+            id: Some(1),
+            key: Some("missing".to_string()),
+            error,
+        });
+        assert_eq!(frame.message_type, WSMessageType::Error);
+        assert_eq!(frame.id, Some(1));
+        assert_eq!(frame.key.as_deref(), Some("missing"));
+        assert_eq!(frame.error.unwrap().code, ErrorCode::UnsupportedMessageType);
+    }
+
+    fn hello(version: u32, encodings: &[&str]) -> WSMessage {
+        WSMessage {
+            data: Some(json!({ "version": version, "encodings": encodings })),
+            ..WSMessage::new(WSMessageType::Hello)
+        }
+    }
+
+    #[test]
+    fn handshake_negotiates_encoding() {
+        let mut fixture = fixture();
+        let _handle = fixture.data("key", 1, Access::ReadWrite);
+        let (welcome, codec) = handshake(
+            &hello(PROTOCOL_VERSION, &["cbor", "json"]),
+            &fixture.connection,
+        )
+        .unwrap();
+        assert_eq!(codec, Codec::Json);
+        assert_eq!(welcome.version, PROTOCOL_VERSION);
+        assert_eq!(welcome.encoding, "json");
+        assert_eq!(welcome.keys, vec!["key".to_string()]);
+
+        let error =
+            handshake(&hello(PROTOCOL_VERSION, &["cbor"]), &fixture.connection).unwrap_err();
+        assert_eq!(error.code, ErrorCode::UnsupportedEncoding);
+    }
+
+    #[test]
+    fn handshake_rejects_other_versions() {
+        let fixture = fixture();
+        let error =
+            handshake(&hello(PROTOCOL_VERSION + 1, &["json"]), &fixture.connection).unwrap_err();
+        assert_eq!(error.code, ErrorCode::VersionMismatch);
+        let error = handshake(
+            &message(WSMessageType::Get, "key", None),
+            &fixture.connection,
+        )
+        .unwrap_err();
+        assert_eq!(error.code, ErrorCode::HandshakeRequired);
+    }
+
+    #[test]
+    fn snapshot_revision_orders_against_writes() {
+        let mut fixture = fixture();
+        let handle = fixture.data("key", 1, Access::ReadWrite);
+        handle.set(2);
+        let before = fixture.broadcast().unwrap();
+        fixture
+            .send(WSMessage::new(WSMessageType::Snapshot))
+            .unwrap();
+        let snapshot = fixture.reply().unwrap();
+        assert_eq!(snapshot.message_type, WSMessageType::Snapshot);
+        // writes in the snapshot are not newer than it, writes after it are
+        assert!(before.revision <= snapshot.revision);
+        assert_eq!(
+            snapshot.data,
+            Some(json!({ "key": { "data": 2, "version": 1 } }))
+        );
+        handle.set(3);
+        let after = fixture.broadcast().unwrap();
+        assert!(after.revision > snapshot.revision);
+    }
+
+    #[test]
+    fn expected_version_conflicts() {
+        let mut fixture = fixture();
+        let handle = fixture.data("key", 1, Access::ReadWrite);
+        handle.set(2);
+        let update = fixture.broadcast().unwrap();
+        assert_eq!(update.version, Some(1));
+
+        let set = |value, expected_version| WSMessage {
+            expected_version: Some(expected_version),
+            ..message(WSMessageType::Set, "key", Some(json!(value)))
+        };
+        let error = fixture.send(set(3, 0)).unwrap_err();
+        assert_eq!(error.code, ErrorCode::VersionConflict);
+        assert_eq!(*handle.get(), 2);
+        // the client is sent what it missed
+        let resend = fixture.reply().unwrap();
+        assert_eq!(resend.data, Some(json!(2)));
+        assert_eq!(resend.version, Some(1));
+
+        fixture.send(set(3, 1)).unwrap();
+        assert_eq!(*handle.get(), 3);
+        assert_eq!(handle.version(), 2);
+    }
+
+    #[test]
+    fn access_is_enforced() {
+        let mut fixture = fixture();
+        let read_only = fixture.data("answer", 1, Access::ReadOnly);
+        let _write_only = fixture.data("secret", 2, Access::WriteOnly);
+        let error = fixture
+            .send(message(WSMessageType::Set, "answer", Some(json!(3))))
+            .unwrap_err();
+        assert_eq!(error.code, ErrorCode::Forbidden);
+        assert_eq!(*read_only.get(), 1);
+        // the client's optimistic value is replaced
+        assert_eq!(fixture.reply().unwrap().data, Some(json!(1)));
+
+        let error = fixture
+            .send(message(WSMessageType::Get, "secret", None))
+            .unwrap_err();
+        assert_eq!(error.code, ErrorCode::Forbidden);
+        fixture
+            .send(message(WSMessageType::Set, "secret", Some(json!(4))))
+            .unwrap();
+        // write-only values are neither resent nor broadcast
+        assert!(fixture.reply().is_none());
+        assert!(fixture.broadcast().is_none());
+
+        fixture
+            .send(WSMessage::new(WSMessageType::Snapshot))
+            .unwrap();
+        let snapshot = fixture.reply().unwrap();
+        assert_eq!(
+            snapshot.data,
+            Some(json!({ "answer": { "data": 1, "version": 0 } }))
+        );
+    }
+
+    #[tokio::test]
+    async fn rpc_replies_go_to_the_caller() {
+        let mut fixture = fixture();
+        let handler: RpcHandler = Arc::new(|request: &Value| {
+            let value: u32 = serde_json::from_value(request.clone())?;
+            let call: RpcCall = Box::pin(async move {
+                match value {
+                    0 => Err("Zero is not allowed".to_string()),
+                    value => Ok(Box::new(value + 1) as Box<dyn Synchronizable>),
+                }
+            });
+            Ok(call)
+        });
+        fixture
+            .connection
+            .rpc_handler_store
+            .write()
+            .insert("increment".to_string(), handler);
+
+        fixture
+            .send(message(WSMessageType::Call, "increment", Some(json!(1))))
+            .unwrap();
+        let reply = to_wire(fixture.client_receiver.recv().await.unwrap());
+        assert_eq!(reply.message_type, WSMessageType::Reply);
+        assert_eq!(reply.id, Some(1));
+        assert_eq!(reply.data, Some(json!(2)));
+        assert!(fixture.broadcast().is_none());
+
+        fixture
+            .send(message(WSMessageType::Call, "increment", Some(json!(0))))
+            .unwrap();
+        let reply = to_wire(fixture.client_receiver.recv().await.unwrap());
+        assert_eq!(reply.message_type, WSMessageType::Error);
+        assert_eq!(reply.id, Some(1));
+        assert_eq!(reply.error.unwrap().code, ErrorCode::RpcFailed);
+
+        let error = fixture
+            .send(message(
+                WSMessageType::Call,
+                "increment",
+                Some(json!("one")),
+            ))
+            .unwrap_err();
+        assert_eq!(error.code, ErrorCode::InvalidPayload);
+        let error = fixture
+            .send(message(WSMessageType::Call, "missing", None))
+            .unwrap_err();
+        assert_eq!(error.code, ErrorCode::UnknownRpc);
+    }
+
+    #[test]
+    fn set_carries_the_throttle() {
+        let mut fixture = fixture();
+        let throttle = Throttle::Interval(std::time::Duration::from_millis(100));
+        let handle = fixture.data("key", 1, Access::ReadWrite).throttle(throttle);
+        handle.set(2);
+        match fixture.broadcast_receiver.try_recv().unwrap() {
+            Message::Set { throttle: sent, .. } => assert_eq!(sent, Some(throttle)),
+            message => panic!("expected a Set, got {:?}", message),
+        }
+    }
 }
@@ -1,12 +1,14 @@
 #[cfg(test)]
-#[macro_use]
-extern crate lazy_static;
-
 mod tests {
-    use std::sync::{Arc, Mutex};
+    use std::{
+        panic::{self, AssertUnwindSafe},
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
 
-    use poca::{include_app_dir, DataHandle, Poca};
+    use poca::{include_app_dir, Concurrency, Poca};
     use serde::{Deserialize, Serialize};
+    use tokio::sync::Barrier;
 
     #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
     struct TestStruct {
@@ -14,48 +16,52 @@ mod tests {
         test_bool: bool,
     }
 
-    lazy_static! {
-        static ref POCA: Poca =
-            Poca::new("localhost:1120", include_app_dir!("tests/empty_assets/"));
-        static ref HANDLE1: DataHandle<i32> = POCA.data("test1", 1);
-        static ref HANDLE2: DataHandle<String> = POCA.data("test2", "test2".to_string());
-        static ref HANDLE3: DataHandle<TestStruct> = POCA.data(
+    fn poca() -> Poca {
+        Poca::new(
+            "localhost:1120",
+            include_app_dir!("tests/empty_assets/"),
+            None,
+        )
+    }
+
+    #[test]
+    fn setting_and_getting() {
+        let poca = poca();
+        let handle1 = poca.data("test1", 1);
+        let handle2 = poca.data("test2", "test2".to_string());
+        let handle3 = poca.data(
             "test3",
             TestStruct {
                 test_field: "test_field".to_string(),
-                test_bool: true
-            }
+                test_bool: true,
+            },
         );
-        static ref HANDLE4: DataHandle<Vec<i32>> = POCA.data("test4", vec![1, 2, 3]);
-    }
 
-    #[test]
-    fn setting_and_getting() {
-        HANDLE1.set(1);
-        assert_eq!(*HANDLE1.get(), 1);
+        handle1.set(1);
+        assert_eq!(*handle1.get(), 1);
 
-        HANDLE1.set(2);
-        assert_eq!(*HANDLE1.get(), 2);
+        handle1.set(2);
+        assert_eq!(*handle1.get(), 2);
 
-        assert_eq!(*HANDLE2.get(), "test2".to_string());
+        assert_eq!(*handle2.get(), "test2".to_string());
 
-        HANDLE2.set("test3".to_string());
-        assert_eq!(*HANDLE2.get(), "test3".to_string());
+        handle2.set("test3".to_string());
+        assert_eq!(*handle2.get(), "test3".to_string());
 
         assert_eq!(
-            *HANDLE3.get(),
+            *handle3.get(),
             TestStruct {
                 test_field: "test_field".to_string(),
                 test_bool: true
             }
         );
 
-        HANDLE3.set(TestStruct {
+        handle3.set(TestStruct {
             test_field: "test_field2".to_string(),
             test_bool: false,
         });
         assert_eq!(
-            *HANDLE3.get(),
+            *handle3.get(),
             TestStruct {
                 test_field: "test_field2".to_string(),
                 test_bool: false
@@ -63,26 +69,353 @@ mod tests {
         );
     }
 
+    #[test]
+    fn separate_instances() {
+        let first = poca();
+        let second = poca();
+        let handle1 = first.data("test1", 1);
+        let handle2 = second.data("test1", 2);
+
+        handle1.set(3);
+        assert_eq!(*handle2.get(), 2);
+    }
+
     #[test]
     fn on_change_handler() {
+        let poca = poca();
+        let handle1 = poca.data("test1", 1);
         let watcher = Arc::new(Mutex::new(false));
         let watcher_clone = watcher.clone();
-        HANDLE1.on_change(move |new_value| {
+        let _subscription = handle1.on_change(move |new_value| {
             println!("{:?}", new_value);
             let mut writer = watcher_clone.lock().unwrap();
             *writer = true;
         });
 
-        HANDLE1.set(3);
+        handle1.set(3);
         assert_eq!(*(watcher.lock().unwrap()), true);
     }
 
+    #[test]
+    fn on_change_handler_unsubscribe() {
+        let poca = poca();
+        let handle6 = poca.data("test6", 0);
+        let counter = Arc::new(Mutex::new(0));
+        let counter_clone = counter.clone();
+        let subscription = handle6.on_change(move |_new_value| {
+            *counter_clone.lock().unwrap() += 1;
+        });
+
+        handle6.set(10);
+        subscription.unsubscribe();
+        handle6.set(11);
+        assert_eq!(*(counter.lock().unwrap()), 1);
+    }
+
     #[test]
     fn on_change_handler_with_inner_set() {
-        let handle5 = POCA.data("test5", true);
-        HANDLE4.on_change(move |_new_value| {
-            handle5.set(false);
+        let poca = poca();
+        let handle4 = poca.data("test4", vec![1, 2, 3]);
+        let handle5 = poca.data("test5", true);
+        let handle5_clone = handle5.clone();
+        let _subscription = handle4.on_change(move |_new_value| {
+            handle5_clone.set(false);
+        });
+        handle4.set(vec![4, 5, 6]);
+        assert!(!*handle5.get());
+    }
+
+    #[test]
+    fn on_change_handlers_see_own_writes() {
+        let poca = poca();
+        let handle21 = poca.data("test21", 0).skip_unchanged();
+        let handle21_clone = handle21.clone();
+        let _subscription = handle21.on_change(move |new_value| {
+            handle21_clone.set(new_value.min(10));
         });
-        HANDLE4.set(vec![4, 5, 6]);
+        let doubled = poca.computed("test29", &handle21, |value| value * 2);
+        handle21.set(15);
+        assert_eq!(*handle21.get(), 10);
+        assert_eq!(*doubled.get(), 20);
+    }
+
+    #[test]
+    fn duplicate_key_keeps_existing() {
+        let poca = poca();
+        let _handle23 = poca.data("test23", 1);
+        let duplicate = panic::catch_unwind(AssertUnwindSafe(|| poca.data("test23", 2)));
+        assert!(duplicate.is_err());
+        assert!(poca.remove_data("test23"));
+    }
+
+    #[test]
+    fn skip_unchanged() {
+        let poca = poca();
+        let handle22 = poca.data("test22", 1).skip_unchanged();
+        let changes = Arc::new(Mutex::new(0));
+        let changes_clone = changes.clone();
+        let _subscription = handle22.on_change(move |_| *changes_clone.lock().unwrap() += 1);
+        handle22.set(1);
+        assert_eq!(*changes.lock().unwrap(), 0);
+        handle22.set(2);
+        assert_eq!(*changes.lock().unwrap(), 1);
+        poca.transaction(|tx| tx.set(&handle22, 2));
+        assert_eq!(*changes.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn transaction_runs_handlers_after_commit() {
+        let poca = poca();
+        let handle8 = poca.data("test8", 0);
+        let handle9 = poca.data("test9", 0);
+        let seen = Arc::new(Mutex::new(None));
+        let seen_clone = seen.clone();
+        let handle9_clone = handle9.clone();
+        let _subscription = handle8.on_change(move |new_value| {
+            *seen_clone.lock().unwrap() = Some((new_value, *handle9_clone.get()));
+        });
+
+        poca.transaction(|tx| {
+            tx.set(&handle8, 1);
+            tx.set(&handle9, 2);
+        });
+        assert_eq!(*seen.lock().unwrap(), Some((1, 2)));
+    }
+
+    #[test]
+    fn transaction_keeps_replaced_elements_apart() {
+        let poca = poca();
+        let old = poca.data("test30", 1);
+        let new = poca.data_or_replace("test30", "one".to_string());
+        poca.transaction(|tx| {
+            tx.set(&old, 5);
+            tx.set(&new, "five".to_string());
+        });
+        assert_eq!(*old.get(), 5);
+        assert_eq!(*new.get(), "five");
+    }
+
+    #[test]
+    fn removing_and_replacing_data() {
+        let poca = poca();
+        let handle10 = poca.data("test10", 1);
+        let replacement = poca.data_or_replace("test10", 2);
+        assert_eq!(*handle10.get(), 1);
+        assert_eq!(*replacement.get(), 2);
+
+        assert!(poca.remove_data("test10"));
+        assert!(!poca.remove_data("test10"));
+        let _handle10 = poca.data("test10", 3);
+    }
+
+    #[test]
+    fn remove_on_drop() {
+        let poca = poca();
+        let handle11 = poca.data("test11", 1).remove_on_drop();
+        let handle11_clone = handle11.clone();
+        drop(handle11);
+        drop(handle11_clone);
+        let _handle11 = poca.data("test11", 2);
+
+        let handle12 = poca.data("test12", 1).remove_on_drop();
+        let _replacement = poca.data_or_replace("test12", 2);
+        drop(handle12);
+        assert!(poca.remove_data("test12"));
+    }
+
+    #[test]
+    fn removed_element_drops_handles_outside_the_store_lock() {
+        let poca = poca();
+        let handle31 = poca.data("test31", 1);
+        let handle32 = poca.data("test32", 1).remove_on_drop();
+        handle31
+            .on_change(move |_| {
+                handle32.get();
+            })
+            .detach();
+        drop(handle31);
+        // dropping the element drops the handler and with it the last handle to test32
+        assert!(poca.remove_data("test31"));
+        assert!(!poca.remove_data("test32"));
+
+        let handle33 = poca.data("test33", 1);
+        let handle34 = poca.data("test34", 1).remove_on_drop();
+        handle33
+            .on_change(move |_| {
+                handle34.get();
+            })
+            .detach();
+        drop(handle33);
+        let _replacement = poca.data_or_replace("test33", 2);
+        assert!(!poca.remove_data("test34"));
+    }
+
+    #[test]
+    fn collection_items() {
+        let poca = poca();
+        let collection = poca.collection::<u32, String>("test13");
+        collection.insert(1, "first".to_string());
+        collection.insert(2, "second".to_string());
+        let changed = Arc::new(Mutex::new(Vec::new()));
+        let changed_clone = changed.clone();
+        let _subscription = collection
+            .on_change(&2, move |new_value| {
+                changed_clone.lock().unwrap().push(new_value)
+            })
+            .unwrap();
+
+        collection.insert(2, "updated".to_string());
+        assert!(collection.update(&1, "also updated".to_string()));
+        assert!(collection.remove(&1));
+        assert!(!collection.update(&1, "gone".to_string()));
+        assert_eq!(collection.item_key(&2), "test13/2");
+        assert_eq!(
+            collection.get(),
+            [(2, "updated".to_string())].into_iter().collect()
+        );
+        assert_eq!(*changed.lock().unwrap(), vec!["updated".to_string()]);
+
+        // items removed by key leave the collection too
+        assert!(poca.remove_data("test13/2"));
+        assert!(collection.is_empty());
+        collection.insert(2, "again".to_string());
+        assert_eq!(collection.len(), 1);
+    }
+
+    #[test]
+    fn collection_key_is_reserved() {
+        let poca = poca();
+        let collection = poca.collection::<u32, String>("test24");
+        let duplicate = panic::catch_unwind(AssertUnwindSafe(|| {
+            poca.collection::<u32, String>("test24")
+        }));
+        assert!(duplicate.is_err());
+        // released with the last handle
+        drop(collection);
+        let _collection = poca.collection::<u32, String>("test24");
+    }
+
+    #[test]
+    fn scoped_keys() {
+        let poca = poca();
+        let settings = poca.scope("settings");
+        let theme = settings.data("theme", "dark".to_string());
+        let font = settings.scope("editor").data("font", 12);
+        assert_eq!(theme.get_key(), "settings.theme");
+        assert_eq!(font.get_key(), "settings.editor.font");
+
+        let _theme = poca.scope("other").data("theme", "light".to_string());
+        assert!(poca.remove_data("settings.theme"));
+    }
+
+    #[test]
+    fn computed_data() {
+        let poca = poca();
+        let handle14 = poca.data("test14", 1);
+        let handle15 = poca.data("test15", 2);
+        let sum = poca.computed("test16", (&handle14, &handle15), |(a, b)| a + b);
+        let parity = poca.computed("test17", &sum, |sum| sum % 2);
+        let changes = Arc::new(Mutex::new(0));
+        let changes_clone = changes.clone();
+        let _subscription = parity.on_change(move |_| *changes_clone.lock().unwrap() += 1);
+        assert_eq!(*sum.get(), 3);
+
+        handle14.set(3);
+        assert_eq!(*sum.get(), 5);
+        assert_eq!(*parity.get(), 1);
+        assert_eq!(*changes.lock().unwrap(), 0);
+
+        handle15.set(3);
+        assert_eq!(*parity.get(), 0);
+        assert_eq!(*changes.lock().unwrap(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "would depend on itself")]
+    fn computed_cycle() {
+        let poca = poca();
+        let handle18 = poca.data("test18", 1);
+        let handle19 = poca.computed("test19", &handle18, |value| value + 1);
+        let handle20 = poca.computed("test20", &handle19, |value| value + 1);
+        poca.remove_data("test18");
+        let _handle18 = poca.computed("test18", &handle20, |value| value + 1);
+    }
+
+    #[test]
+    fn computed_failure_leaves_no_dependency() {
+        let poca = poca();
+        let handle25 = poca.data("test25", 1);
+        let handle26 = poca.data("test26", 1);
+        let duplicate = panic::catch_unwind(AssertUnwindSafe(|| {
+            poca.computed("test26", &handle25, |value| value + 1)
+        }));
+        assert!(duplicate.is_err());
+        // test26 does not depend on test25, so this is no cycle
+        poca.remove_data("test25");
+        let _handle25 = poca.computed("test25", &handle26, |value| value + 1);
+    }
+
+    #[test]
+    fn computed_remove_on_drop() {
+        let poca = poca();
+        let handle27 = poca.data("test27", 1);
+        let computed = poca
+            .computed("test28", &handle27, |value| value + 1)
+            .remove_on_drop();
+        handle27.set(2);
+        assert_eq!(*computed.get(), 3);
+        drop(computed);
+        assert!(!poca.remove_data("test28"));
+        handle27.set(3);
+    }
+
+    #[tokio::test]
+    async fn on_change_async_handler_serial() {
+        let poca = poca();
+        let handle7 = poca.data("test7", 0);
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let seen_clone = seen.clone();
+        let _subscription = handle7.on_change_async(Concurrency::Serial, move |new_value| {
+            let seen = seen_clone.clone();
+            async move {
+                // the first handler finishes last unless the second one waits for it
+                if new_value == 1 {
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                }
+                seen.lock().unwrap().push(new_value);
+            }
+        });
+
+        handle7.set(1);
+        handle7.set(2);
+        handle7.settled().await;
+        assert_eq!(*seen.lock().unwrap(), vec![1, 2]);
+    }
+
+    #[tokio::test]
+    async fn on_change_async_handler_concurrent() {
+        let poca = poca();
+        let handle8 = poca.data("test8", 0);
+        // both handlers have to be running at once to get past the barrier
+        let barrier = Arc::new(Barrier::new(2));
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let seen_clone = seen.clone();
+        let _subscription = handle8.on_change_async(Concurrency::Concurrent, move |new_value| {
+            let barrier = barrier.clone();
+            let seen = seen_clone.clone();
+            async move {
+                barrier.wait().await;
+                seen.lock().unwrap().push(new_value);
+            }
+        });
+
+        handle8.set(1);
+        handle8.set(2);
+        tokio::time::timeout(Duration::from_secs(5), handle8.settled())
+            .await
+            .expect("concurrent handlers ran one after the other");
+        let mut seen = seen.lock().unwrap().clone();
+        seen.sort();
+        assert_eq!(seen, vec![1, 2]);
     }
 }
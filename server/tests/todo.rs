@@ -1,21 +1,30 @@
 #[cfg(test)]
-#[macro_use]
-extern crate lazy_static;
-
 mod tests {
-    use poca::{include_app_dir, DataHandle, Poca};
+    use std::sync::{Arc, Mutex};
 
-    lazy_static! {
-        static ref POCA: Poca =
-            Poca::new("localhost:1120", include_app_dir!("tests/empty_assets/"));
-        static ref HANDLE1: DataHandle<i32> = POCA.data("test1", 1);
-    }
+    use poca::{include_app_dir, Poca};
 
-    //? not sure if this always works
     #[test]
     fn on_change_handler_with_inner_self_set() {
-        HANDLE1.on_change(|_new_value| {
-            HANDLE1.set(2);
-        })
+        let poca = Poca::new(
+            "localhost:1120",
+            include_app_dir!("tests/empty_assets/"),
+            None,
+        );
+        let handle1 = poca.data("test1", 0).skip_unchanged();
+        let handle1_clone = handle1.clone();
+        let runs = Arc::new(Mutex::new(0));
+        let runs_clone = runs.clone();
+        handle1
+            .on_change(move |_new_value| {
+                *runs_clone.lock().unwrap() += 1;
+                handle1_clone.set(2);
+            })
+            .detach();
+        handle1.set(1);
+        assert_eq!(*handle1.get(), 2);
+        // once for the write of 1 and once for its own write of 2,
+        // writing 2 again changes nothing and ends the loop
+        assert_eq!(*runs.lock().unwrap(), 2);
     }
 }